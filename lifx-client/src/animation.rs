@@ -0,0 +1,239 @@
+//! Client-side color animation.
+//!
+//! Devices can fade between two colors on their own using [`SetColor::duration`], but that can't express fades through several colors, and
+//! separate devices drift apart over long fades. An [`Animation`] instead computes each frame on the client and sends it to every device
+//! at a fixed frame rate, so devices stay in sync and can follow any number of keyframes.
+//!
+//! ```no_run
+//! # async fn animate(client: &mut lifx_client::Client, left: lifx_client::DeviceAddress, right: lifx_client::DeviceAddress) -> Result<(), lifx_client::Error> {
+//! use std::time::Duration;
+//! use lifx_client::animation::{Animation, Keyframe, Track};
+//! use lifx_proto::color::{Hsbk, Kelvin};
+//!
+//! let red = Hsbk { hue: 0, saturation: 65535, brightness: 65535, temperature: Kelvin::new(3500) };
+//! let blue = Hsbk { hue: 43690, ..red };
+//!
+//! // Sweep the two lights in opposite directions
+//! let animation = Animation::new()
+//!     .with_track(Track::new(left, vec![Keyframe::new(Duration::ZERO, red), Keyframe::new(Duration::from_secs(2), blue)]))
+//!     .with_track(Track::new(right, vec![Keyframe::new(Duration::ZERO, blue), Keyframe::new(Duration::from_secs(2), red)]));
+//! animation.run(client).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::time::Duration;
+
+use futures::future::try_join_all;
+use lifx_proto::color::{BrightnessCurve, Hsbk};
+use lifx_proto::duration::ProtocolDuration;
+use lifx_proto::message::SetColor;
+use lifx_proto::Message;
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::{Client, DeviceAddress, Error};
+
+/// A color that a device should reach at some point in an animation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keyframe {
+    /// Time since the start of the animation
+    pub offset: Duration,
+    pub color: Hsbk,
+}
+
+impl Keyframe {
+    pub const fn new(offset: Duration, color: Hsbk) -> Keyframe {
+        Keyframe { offset, color }
+    }
+}
+
+/// The keyframes for a single device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Track {
+    address: DeviceAddress,
+    // Sorted by offset
+    keyframes: Vec<Keyframe>,
+}
+
+impl Track {
+    /// Create a track for the device at `address`. The keyframes don't need to be in order.
+    pub fn new<I: IntoIterator<Item = Keyframe>>(address: DeviceAddress, keyframes: I) -> Track {
+        let mut keyframes: Vec<Keyframe> = keyframes.into_iter().collect();
+        // Stable, so if several keyframes have the same offset, the last one wins
+        keyframes.sort_by_key(|keyframe| keyframe.offset);
+        Track { address, keyframes }
+    }
+
+    pub fn address(&self) -> DeviceAddress {
+        self.address
+    }
+
+    /// The keyframes, in order
+    pub fn keyframes(&self) -> &[Keyframe] {
+        &self.keyframes
+    }
+
+    /// Offset of the last keyframe
+    pub fn duration(&self) -> Duration {
+        self.keyframes.last().map_or(Duration::ZERO, |keyframe| keyframe.offset)
+    }
+
+    /// The device's color at `elapsed` into the animation, or `None` if there are no keyframes. Before the first keyframe, the device
+    /// holds the first keyframe's color, and after the last keyframe it holds the last one's. Between keyframes, brightness changes evenly
+    /// along `curve`.
+    pub fn color_at(&self, elapsed: Duration, curve: &BrightnessCurve) -> Option<Hsbk> {
+        // Index of the first keyframe after `elapsed`
+        let next = self.keyframes.partition_point(|keyframe| keyframe.offset <= elapsed);
+        match (next.checked_sub(1).map(|idx| &self.keyframes[idx]), self.keyframes.get(next)) {
+            (Some(from), Some(to)) => {
+                let t = (elapsed - from.offset).as_secs_f32() / (to.offset - from.offset).as_secs_f32();
+                Some(from.color.interpolate(to.color, t, curve))
+            }
+            (Some(keyframe), None) | (None, Some(keyframe)) => Some(keyframe.color),
+            (None, None) => None,
+        }
+    }
+}
+
+/// A set of [`Track`]s played together. See the [module documentation](self) for an example.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Animation {
+    tracks: Vec<Track>,
+    frame_interval: Duration,
+    brightness_curve: BrightnessCurve,
+}
+
+impl Animation {
+    /// The default frame rate, which matches the rate LIFX recommends not exceeding for a single device
+    pub const DEFAULT_FRAME_RATE: u32 = 20;
+
+    /// The highest supported frame rate. Transition times are sent in whole milliseconds, so faster frames can't fade between each other.
+    pub const MAX_FRAME_RATE: u32 = 1000;
+
+    /// Create an empty animation running at [`Animation::DEFAULT_FRAME_RATE`], with a linear brightness curve
+    pub fn new() -> Animation {
+        Animation {
+            tracks: Vec::new(),
+            frame_interval: Duration::from_secs(1) / Animation::DEFAULT_FRAME_RATE,
+            brightness_curve: BrightnessCurve::default(),
+        }
+    }
+
+    pub fn with_track(mut self, track: Track) -> Animation {
+        self.tracks.push(track);
+        self
+    }
+
+    /// Send `frames_per_second` frames per second to each device. Frame rates above the client's
+    /// [rate limit](crate::Config::rate_limit) make frames queue up, unless [coalescing](crate::Config::coalesce) is enabled.
+    ///
+    /// # Panics
+    /// If `frames_per_second` is 0 or greater than [`Animation::MAX_FRAME_RATE`]
+    pub fn with_frame_rate(mut self, frames_per_second: u32) -> Animation {
+        assert!(frames_per_second > 0, "frame rate must be positive");
+        assert!(frames_per_second <= Animation::MAX_FRAME_RATE, "frame rate must be at most {}", Animation::MAX_FRAME_RATE);
+        self.frame_interval = Duration::from_secs(1) / frames_per_second;
+        self
+    }
+
+    /// Change brightness evenly along `curve` between keyframes. [`BrightnessCurve::CieLightness`] makes fades look even, especially
+    /// near black.
+    pub fn with_brightness_curve(mut self, curve: BrightnessCurve) -> Animation {
+        self.brightness_curve = curve;
+        self
+    }
+
+    pub fn brightness_curve(&self) -> &BrightnessCurve {
+        &self.brightness_curve
+    }
+
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    /// Time between frames
+    pub fn frame_interval(&self) -> Duration {
+        self.frame_interval
+    }
+
+    /// Offset of the last keyframe in any track
+    pub fn duration(&self) -> Duration {
+        self.tracks.iter().map(Track::duration).max().unwrap_or(Duration::ZERO)
+    }
+
+    /// Play the animation, returning once the final frame has been sent.
+    ///
+    /// Frames are sent with [`Client::send_async`], and each one asks the device to fade to its color over one frame interval, which smooths
+    /// out the steps between frames. Each frame is queued for every device at once, so a device whose queue is full doesn't hold up the others.
+    /// If sending falls behind, frames are skipped rather than sent in a burst.
+    pub async fn run(&self, client: &mut Client) -> Result<(), Error> {
+        let duration = self.duration();
+        // At most a second, so this never saturates
+        let frame_duration = ProtocolDuration::saturating(self.frame_interval);
+        let mut ticker = tokio::time::interval(self.frame_interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let start = Instant::now();
+
+        loop {
+            ticker.tick().await;
+            let elapsed = start.elapsed().min(duration);
+            let sends = self.tracks.iter().filter_map(|track| {
+                let color = track.color_at(elapsed, &self.brightness_curve)?;
+                let message = Message::SetColor(SetColor { color, duration: frame_duration });
+                let mut client = client.clone();
+                Some(async move { client.send_async(track.address, message).await })
+            });
+            try_join_all(sends).await?;
+
+            if elapsed >= duration {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Default for Animation {
+    fn default() -> Animation {
+        Animation::new()
+    }
+}
+
+#[test]
+fn test_color_at() {
+    use lifx_proto::color::Kelvin;
+
+    let color = |hue| Hsbk { hue, saturation: 65535, brightness: 65535, temperature: Kelvin::new(3500) };
+    let secs = Duration::from_secs;
+    let linear = &BrightnessCurve::Linear;
+
+    let track = Track::new(DeviceAddress::all(), vec![Keyframe::new(secs(3), color(3000)), Keyframe::new(secs(1), color(1000))]);
+    assert_eq!(track.duration(), secs(3));
+    assert_eq!(track.keyframes()[0].offset, secs(1));
+
+    assert_eq!(track.color_at(Duration::ZERO, linear), Some(color(1000)));
+    assert_eq!(track.color_at(secs(1), linear), Some(color(1000)));
+    assert_eq!(track.color_at(secs(2), linear), Some(color(2000)));
+    assert_eq!(track.color_at(Duration::from_millis(2500), linear), Some(color(2500)));
+    assert_eq!(track.color_at(secs(3), linear), Some(color(3000)));
+    assert_eq!(track.color_at(secs(10), linear), Some(color(3000)));
+
+    // Jumps happen when keyframes share an offset
+    let jump = Track::new(DeviceAddress::all(), vec![Keyframe::new(secs(1), color(1000)), Keyframe::new(secs(1), color(5000))]);
+    assert_eq!(jump.color_at(Duration::ZERO, linear), Some(color(1000)));
+    assert_eq!(jump.color_at(secs(1), linear), Some(color(5000)));
+
+    assert_eq!(Track::new(DeviceAddress::all(), vec![]).color_at(secs(1), linear), None);
+
+    let animation = Animation::new().with_track(track).with_track(jump).with_frame_rate(50);
+    assert_eq!(animation.duration(), secs(3));
+    assert_eq!(animation.frame_interval(), Duration::from_millis(20));
+    assert_eq!(Animation::new().with_frame_rate(Animation::MAX_FRAME_RATE).frame_interval(), Duration::from_millis(1));
+    assert!(std::panic::catch_unwind(|| Animation::new().with_frame_rate(0)).is_err());
+    assert!(std::panic::catch_unwind(|| Animation::new().with_frame_rate(2_000_000_000)).is_err());
+
+    // Perceptual curves spend more of a fade in dim light
+    let dim = Hsbk { brightness: 0, ..color(0) };
+    let fade = Track::new(DeviceAddress::all(), vec![Keyframe::new(Duration::ZERO, dim), Keyframe::new(secs(2), color(0))]);
+    assert_eq!(fade.color_at(secs(1), linear).unwrap().brightness, 32768);
+    assert_eq!(fade.color_at(secs(1), &BrightnessCurve::CieLightness).unwrap().brightness, 12071);
+}
@@ -1,20 +1,33 @@
+use std::convert::TryFrom;
+use std::future::Future;
+use std::net::IpAddr;
 use std::time::Duration;
 
-use lifx_proto::{Message, ProtocolError, message::*, color::Hsbk};
+use lifx_proto::{Message, Query, message::*, color::Hsbk, duration::ProtocolDuration, products::Features};
 use tokio::net::{UdpSocket, ToSocketAddrs};
 use tokio::sync::{mpsc, broadcast, oneshot};
 use tokio_util::udp::UdpFramed;
 
-use crate::DeviceAddress;
+use crate::{Config, DeviceAddress};
+use crate::discovery::Interface;
 use crate::codec::Codec;
 use crate::connection::{Connection, Request, Response, InboundMessage};
 use crate::error::Error;
+use crate::rate_limit::{QueuePermits, RateLimiter, RateLimitMetrics};
+use crate::transport::Transport;
+
+/// Number of discovered devices that can be waiting to be read from a discovery receiver. Scans can turn up many devices at once, and any beyond
+/// this are lost if the receiver isn't read in time.
+const DISCOVERY_CAPACITY: usize = 1024;
 
 pub struct Client {
-    requests: mpsc::UnboundedSender<Request>,
+    requests: mpsc::Sender<Request>,
+    permits: QueuePermits,
     // Only needed for Clone
     discovery_tx: broadcast::Sender<DeviceAddress>,
-    discovery: broadcast::Receiver<DeviceAddress>,
+    // Never read, but keeps the discovery channel open while no one is subscribed
+    _discovery: broadcast::Receiver<DeviceAddress>,
+    rate_limit_metrics: RateLimitMetrics,
 }
 
 impl Client {
@@ -31,75 +44,227 @@ impl Client {
     }
 
     pub fn with_socket_and_source(socket: UdpSocket, source: u32) -> Result<(Client, Connection), Error> {
+        Client::with_socket_and_config(socket, source, Config::default())
+    }
+
+    /// Create a new `Client` using `socket`, with non-default settings. Fails with [`Error::InvalidConfig`] if `config` is invalid.
+    pub fn with_socket_and_config(socket: UdpSocket, source: u32, config: Config) -> Result<(Client, Connection), Error> {
         socket.set_broadcast(true)?; // Needed for discovery
+        Client::with_transport(UdpFramed::new(socket, Codec), source, config)
+    }
 
-        let (request_tx, request_rx) = mpsc::unbounded_channel();
-        let (discovery_tx, discovery_rx) = broadcast::channel(10);
+    /// Create a new `Client` that communicates over `transport` instead of a UDP socket. Fails with [`Error::InvalidConfig`] if `config` is invalid.
+    pub fn with_transport<T: Transport>(transport: T, source: u32, config: Config) -> Result<(Client, Connection<T>), Error> {
+        config.validate()?;
+        let (request_tx, request_rx) = mpsc::channel(config.max_queued_requests);
+        let (discovery_tx, discovery_rx) = broadcast::channel(DISCOVERY_CAPACITY);
+        let rate_limit_metrics = RateLimitMetrics::default();
         let conn = Connection::new(
-            UdpFramed::new(socket, Codec),
+            transport,
             source,
             request_rx,
-            discovery_tx.clone()
+            discovery_tx.clone(),
+            RateLimiter::new(&config, rate_limit_metrics.clone()),
         );
 
         let client = Client {
             requests: request_tx,
-            discovery: discovery_rx,
+            permits: QueuePermits::new(config.queue_capacity),
+            _discovery: discovery_rx,
             discovery_tx,
+            rate_limit_metrics,
         };
 
         Ok((client, conn))
     }
 
+    /// Statistics on how long requests have been held back by the per-device rate limit
+    pub fn rate_limit_metrics(&self) -> &RateLimitMetrics {
+        &self.rate_limit_metrics
+    }
+
     // Higher-level operations
 
-    pub fn send_discovery(&mut self) -> Result<broadcast::Receiver<DeviceAddress>, Error> {
-        self.send_async(DeviceAddress::all(), Message::GetService)?;
+    /// Subscribe to devices found by discovery, without sending any discovery messages. This is useful for seeing the results of discovery
+    /// messages sent with [`Client::send_async`].
+    pub fn subscribe_discovery(&self) -> broadcast::Receiver<DeviceAddress> {
+        self.discovery_tx.subscribe()
+    }
+
+    pub async fn send_discovery(&mut self) -> Result<broadcast::Receiver<DeviceAddress>, Error> {
+        self.send_async(DeviceAddress::all(), Message::GetService).await?;
         Ok(self.discovery_tx.subscribe())
     }
+
+    /// Discover devices by sending a discovery message to the directed broadcast address of each of `interfaces`. On hosts attached to several networks,
+    /// this finds devices on all of them, whereas [`Client::send_discovery`] only reaches the network of the default route.
+    ///
+    /// Use [`interfaces`](crate::discovery::interfaces) to list the available interfaces, filtering it to choose specific ones.
+    pub async fn send_discovery_on(&mut self, interfaces: &[Interface]) -> Result<broadcast::Receiver<DeviceAddress>, Error> {
+        let receiver = self.discovery_tx.subscribe();
+        for interface in interfaces {
+            tracing::debug!("Sending discovery on {} to {}", interface.name(), interface.broadcast());
+            self.send_async(DeviceAddress::all_at(interface.broadcast().into()), Message::GetService).await?;
+        }
+        Ok(receiver)
+    }
+
+    /// Discover devices by sending a discovery message to each address in `hosts`, waiting `interval` between each one, or not waiting at all if
+    /// it's zero. This is slower than [`Client::send_discovery`], but works on networks that filter broadcast traffic.
+    ///
+    /// The scan runs in a background task, so devices can be read from the returned receiver while it's still going. Discovered devices are sent to
+    /// the same channel as for [`Client::send_discovery`], and the receiver is subscribed before any messages are sent, so it will see every device
+    /// that responds to the scan.
+    ///
+    /// # Panics
+    /// If called outside of a Tokio runtime
+    ///
+    /// ```no_run
+    /// # async fn scan(client: &lifx_client::Client) -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::time::Duration;
+    /// use lifx_client::discovery::Ipv4Cidr;
+    ///
+    /// let subnet: Ipv4Cidr = "192.168.1.0/24".parse()?;
+    /// let mut discovery = client.scan(subnet.hosts().map(Into::into), Duration::from_millis(5));
+    /// while let Ok(device) = discovery.recv().await {
+    ///     println!("Found {}", device);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn scan<I>(&self, hosts: I, interval: Duration) -> broadcast::Receiver<DeviceAddress>
+    where
+        I: IntoIterator<Item = IpAddr>,
+        I::IntoIter: Send + 'static,
+    {
+        let receiver = self.discovery_tx.subscribe();
+        let hosts = hosts.into_iter();
+        let mut client = self.clone();
+        tokio::spawn(async move {
+            // `interval` panics on a zero period
+            let mut ticker = if interval > Duration::ZERO { Some(tokio::time::interval(interval)) } else { None };
+            for host in hosts {
+                if let Some(ref mut ticker) = ticker {
+                    ticker.tick().await;
+                }
+                if let Err(err) = client.send_async(DeviceAddress::all_at(host), Message::GetService).await {
+                    tracing::warn!("Stopping scan: {}", err);
+                    return;
+                }
+            }
+        });
+        receiver
+    }
     
     pub async fn get_label(&mut self, address: DeviceAddress) -> Result<String, Error> {
-        let message = self.send_with_response(address, Message::GetLabel).await?;
-        match message.into_message() {
-            Message::StateLabel(inner) => Ok(inner.label.into_string()),
-            other => Err(Error::Protocol(ProtocolError::UnexpectedMessage(other.message_type())))
-        }
+        let reply = self.query(address, GetLabel).await?;
+        Ok(reply.label.into_string())
     }
 
     pub async fn get_light_state(&mut self, address: DeviceAddress) -> Result<State, Error> {
-        let message = self.send_with_response(address, Message::Get).await?;
-        match message.into_message() {
-            Message::State(inner) => Ok(inner),
-            other => Err(Error::Protocol(ProtocolError::UnexpectedMessage(other.message_type())))
-        }
+        self.query(address, Get).await
+    }
+
+    /// Look up what a device can do, taking its firmware version into account. Returns `None` if the device is an unknown product.
+    ///
+    /// Colors can be checked against the device's supported color temperatures with [`Features::check_color`].
+    pub async fn get_features(&mut self, address: DeviceAddress) -> Result<Option<Features>, Error> {
+        let version = self.query(address, GetVersion).await?;
+        let product = match version.product() {
+            Some(product) => product,
+            None => return Ok(None),
+        };
+        let firmware = self.query(address, GetHostFirmware).await?;
+        Ok(Some(product.features_with_firmware(firmware.version)))
     }
 
+    /// Set a device's color, fading to it over `transition_duration`. Fails with [`ProtocolError::DurationOutOfRange`](lifx_proto::ProtocolError::DurationOutOfRange) if the transition is
+    /// longer than the protocol allows.
     pub async fn set_light_color(&mut self, address: DeviceAddress, color: Hsbk, transition_duration: Duration) -> Result<(), Error> {
-        let message = Message::SetColor(SetColor { color, duration: transition_duration });
+        let duration = ProtocolDuration::try_from(transition_duration)?;
+        let message = Message::SetColor(SetColor { color, duration });
         // TODO: flag for sending async or not
         self.send_with_acknowledgement(address, message).await
     }
 
     // Lower-level functions to send/receive messages directly
 
-    pub fn send_async(&mut self, address: DeviceAddress, message: Message) -> Result<(), Error> {
-        self.send(Request::new(address, message, None))
+    /// Send `query` to a device and wait for its reply. This fails with [`ProtocolError::UnexpectedMessage`](lifx_proto::ProtocolError::UnexpectedMessage)
+    /// if the device replies with the wrong type of message.
+    ///
+    /// ```no_run
+    /// # async fn query(client: &mut lifx_client::Client, address: lifx_client::DeviceAddress) -> Result<(), lifx_client::Error> {
+    /// use lifx_proto::message::Get;
+    ///
+    /// let state = client.query(address, Get).await?;
+    /// println!("{} is at {:?}", state.label, state.color);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn query<Q: Query>(&mut self, address: DeviceAddress, query: Q) -> Result<Q::Reply, Error> {
+        let reply = self.send_with_response(address, query.into()).await?;
+        Ok(Q::reply(reply.into_message())?)
     }
 
+    /// Send a message without waiting for a response. If the request queue is full, this waits until there's room.
+    pub async fn send_async(&mut self, address: DeviceAddress, message: Message) -> Result<(), Error> {
+        self.send(Request::new(address, message, None)).await
+    }
+
+    /// Send a message without waiting for a response, failing with [`Error::QueueFull`] instead of waiting if the device's request queue is full.
+    pub fn try_send(&mut self, address: DeviceAddress, message: Message) -> Result<(), Error> {
+        let permit = self.permits.try_acquire(address.service_address).ok_or(Error::QueueFull)?;
+        self.requests.try_send(Request::new(address, message, None).with_permit(permit)).map_err(|err| match err {
+            mpsc::error::TrySendError::Full(_) => Error::QueueFull,
+            mpsc::error::TrySendError::Closed(_) => Error::ConnectionClosed,
+        })
+    }
+
+    /// Send a message and wait for the device's reply. Replies can be lost, so this may never complete. Use a timeout such as
+    /// [`tokio::time::timeout`] to give up on it, which frees up everything the request was using.
     pub async fn send_with_response(&mut self, address: DeviceAddress, message: Message) -> Result<InboundMessage, Error> {
         let (tx, rx) = oneshot::channel();
-        self.send(Request::new(address, message, Some(Response::Reply(tx))))?;
+        self.send(Request::new(address, message, Some(Response::reply(tx)))).await?;
         rx.await.map_err(|_| Error::ConnectionClosed)
     }
 
     pub async fn send_with_acknowledgement(&mut self, address: DeviceAddress, message: Message) -> Result<(), Error> {
         let (tx, rx) = oneshot::channel();
-        self.send(Request::new(address, message, Some(Response::Acknowledgement(tx))))?;
+        self.send(Request::new(address, message, Some(Response::acknowledgement(tx)))).await?;
         rx.await.map_err(|_| Error::ConnectionClosed)
     }
 
-    fn send(&mut self, request: Request) -> Result<(), Error> {
-        self.requests.send(request).map_err(|_| Error::ConnectionClosed)
+    /// Send a message requiring both an acknowledgement and a reply. Once the message is queued, this returns two futures, which resolve when the
+    /// acknowledgement and reply arrive. They can be awaited in either order, or separately.
+    ///
+    /// ```no_run
+    /// # async fn rename(client: &mut lifx_client::Client, address: lifx_client::DeviceAddress) -> Result<(), lifx_client::Error> {
+    /// use lifx_proto::label::Label;
+    /// use lifx_proto::message::SetLabel;
+    ///
+    /// let message = SetLabel { label: Label::new("Kitchen") }.into();
+    /// let (acknowledged, reply) = client.send_with_acknowledgement_and_response(address, message).await?;
+    /// acknowledged.await?;
+    /// println!("Renamed, device replied with {:?}", reply.await?.message());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn send_with_acknowledgement_and_response(
+        &mut self,
+        address: DeviceAddress,
+        message: Message,
+    ) -> Result<(impl Future<Output = Result<(), Error>>, impl Future<Output = Result<InboundMessage, Error>>), Error> {
+        let (ack_tx, ack_rx) = oneshot::channel();
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(Request::new(address, message, Some(Response::acknowledgement_and_reply(ack_tx, reply_tx)))).await?;
+        let acknowledged = async move { ack_rx.await.map_err(|_| Error::ConnectionClosed) };
+        let reply = async move { reply_rx.await.map_err(|_| Error::ConnectionClosed) };
+        Ok((acknowledged, reply))
+    }
+
+    async fn send(&mut self, request: Request) -> Result<(), Error> {
+        let permit = self.permits.acquire(request.address.service_address).await;
+        self.requests.send(request.with_permit(permit)).await.map_err(|_| Error::ConnectionClosed)
     }
 }
 
@@ -108,9 +273,60 @@ impl Clone for Client {
         let discovery_tx = self.discovery_tx.clone();
         Client {
             requests: self.requests.clone(),
-            discovery: discovery_tx.subscribe(),
+            permits: self.permits.clone(),
+            _discovery: discovery_tx.subscribe(),
             discovery_tx,
+            rate_limit_metrics: self.rate_limit_metrics.clone(),
         }
     }
 }
-// TODO: better discovery handling?
\ No newline at end of file
+// TODO: better discovery handling?
+#[test]
+fn test_invalid_queue_capacity() {
+    use crate::transport::ChannelTransport;
+
+    for queue_capacity in [0, usize::MAX] {
+        let (transport, _device) = ChannelTransport::pair("10.0.0.1:56700".parse().unwrap(), "10.0.0.2:56700".parse().unwrap());
+        let config = Config { queue_capacity, ..Config::default() };
+        assert!(matches!(Client::with_transport(transport, 1234, config), Err(Error::InvalidConfig(_))));
+    }
+}
+
+#[tokio::test]
+async fn test_queue_full() {
+    use futures::StreamExt;
+    use crate::transport::ChannelTransport;
+
+    let (transport, mut device) = ChannelTransport::pair("10.0.0.1:56700".parse().unwrap(), "10.0.0.2:56700".parse().unwrap());
+    let config = Config { rate_limit: None, queue_capacity: 2, max_queued_requests: 3, ..Config::default() };
+    let (mut client, conn) = Client::with_transport(transport, 1234, config).unwrap();
+    let address = DeviceAddress::all_at(device.local_addr().ip());
+    let other = |host: u8| DeviceAddress::all_at([10, 0, 0, host].into());
+
+    // Nothing is draining the queue until the connection runs
+    client.try_send(address, Message::GetLabel).unwrap();
+    client.try_send(address, Message::GetLabel).unwrap();
+    assert!(matches!(client.try_send(address, Message::GetLabel), Err(Error::QueueFull)));
+
+    // Other devices have their own queues, up to the overall limit
+    client.try_send(other(3), Message::GetHostFirmware).unwrap();
+    assert!(matches!(client.try_send(other(4), Message::GetHostFirmware), Err(Error::QueueFull)));
+
+    // Async sends wait for space instead of failing
+    let mut waiting = client.clone();
+    let send = tokio::spawn(async move { waiting.send_async(address, Message::GetVersion).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!send.is_finished());
+
+    tokio::spawn(conn);
+    send.await.unwrap().unwrap();
+    let mut received = Vec::new();
+    for _ in 0..4 {
+        let (packet, _) = device.next().await.unwrap().unwrap();
+        received.push(packet.message().clone());
+    }
+    // Devices take turns, so only the order for each device is fixed
+    let device_one: Vec<_> = received.iter().filter(|message| **message != Message::GetHostFirmware).cloned().collect();
+    assert_eq!(device_one, vec![Message::GetLabel, Message::GetLabel, Message::GetVersion]);
+    assert!(received.contains(&Message::GetHostFirmware));
+}
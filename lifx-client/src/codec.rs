@@ -1,5 +1,5 @@
 use bytes::{Buf, BytesMut};
-use lifx_proto::{Packet, Header};
+use lifx_proto::{Packet, Header, ProtocolError};
 use tokio_util::codec::{Decoder, Encoder};
 
 use crate::error::Error;
@@ -18,7 +18,7 @@ impl Encoder<Packet> for Codec {
         dst: &mut BytesMut,
     ) -> Result<(), Self::Error> {
         dst.reserve(packet.len());
-        packet.encode(dst);
+        packet.encode(dst)?;
         Ok(())
     }
 }
@@ -28,36 +28,64 @@ impl Decoder for Codec {
 
     type Error = Error;
 
+    /// Decode the datagram in `src`. UDP delivers whole datagrams, so one that's too short for its header or its size field is malformed rather
+    /// than incomplete. Malformed datagrams are discarded, so that the next call moves on to the next datagram.
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // TODO: since we're dealing with UDP, should we reject if there's insufficient data instead of returning Ok(None)?
-        if src.len() < Header::HEADER_SIZE {
-            // Not enough data to read the message header
-            src.reserve(Header::HEADER_SIZE);
+        if src.is_empty() {
             return Ok(None);
         }
 
-        // Peek at the message size field so we know if all the data has arrived
-        let mut size_bytes = [0u8; 2];
-        size_bytes.copy_from_slice(&src[..2]);
-        let size = u16::from_le_bytes(size_bytes) as usize;
-
-        if size > MAX_PACKET_SIZE {
-            return Err(Error::Network(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Packet of length {} is too large", size),
-            )));
+        let result = decode_datagram(src);
+        match result {
+            Ok(_) => src.advance(size_field(src)),
+            Err(_) => src.clear(),
         }
+        result.map(Some)
+    }
+}
 
-        if src.len() < size {
-            // The full packet has not arrived, so reserve capacity for when it does and signal that more data is needed
-            src.reserve(size);
-            return Ok(None);
-        }
+/// The size field of a packet with at least a full header
+fn size_field(src: &[u8]) -> usize {
+    u16::from_le_bytes([src[0], src[1]]) as usize
+}
 
-        let mut data = &src[..size];
-        let packet = Packet::decode(&mut data)?;
-        src.advance(size);
+fn decode_datagram(src: &[u8]) -> Result<Packet, Error> {
+    if src.len() < Header::HEADER_SIZE {
+        return Err(ProtocolError::TooShort { expected: Header::HEADER_SIZE, actual: src.len() }.into());
+    }
 
-        Ok(Some(packet))
+    let size = size_field(src);
+    if size > MAX_PACKET_SIZE {
+        return Err(Error::Network(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Packet of length {} is too large", size),
+        )));
     }
+    if src.len() < size {
+        return Err(ProtocolError::TooShort { expected: size, actual: src.len() }.into());
+    }
+
+    Ok(Packet::decode(&mut &src[..size])?)
+}
+
+#[test]
+fn test_decode_malformed() {
+    use lifx_proto::message::GetLabel;
+    use lifx_proto::MessageType;
+
+    // A header for a SetColor, but without the payload
+    let mut data = Vec::new();
+    Packet::builder(GetLabel).build().encode(&mut data).unwrap();
+    data[32..34].copy_from_slice(&u16::from(MessageType::SetColor).to_le_bytes());
+
+    let mut src = BytesMut::from(&data[..]);
+    assert!(matches!(Codec.decode(&mut src), Err(Error::Protocol(_))));
+    // The bad datagram is discarded, so it isn't decoded again
+    assert!(src.is_empty());
+
+    // UDP datagrams arrive whole, so a short one won't be completed later
+    let mut src = BytesMut::from(&data[..10]);
+    assert!(matches!(Codec.decode(&mut src), Err(Error::Protocol(ProtocolError::TooShort { expected: 36, actual: 10 }))));
+    assert!(src.is_empty());
+    assert!(matches!(Codec.decode(&mut src), Ok(None)));
 }
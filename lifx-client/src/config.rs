@@ -0,0 +1,55 @@
+use crate::error::Error;
+use crate::rate_limit::RateLimit;
+
+/// Settings for a [`Client`](crate::Client) and its [`Connection`](crate::Connection)
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Limit on how quickly messages are sent to each device, or `None` to send them as quickly as possible. Defaults to
+    /// [`RateLimit::RECOMMENDED`].
+    pub rate_limit: Option<RateLimit>,
+
+    /// Whether or not to coalesce requests that set device state. If enabled, a `SetColor` request replaces an earlier `SetColor` request to the
+    /// same device that is still waiting to be sent, as long as nothing else has been queued for that device since, so only the latest color goes out
+    /// and requests are never reordered. Anyone waiting on the replaced request is notified when the newer one is acknowledged. Defaults to `false`.
+    pub coalesce: bool,
+
+    /// Maximum number of requests that can be waiting to be sent to each device. Once a device's queue is full, async send methods wait for space
+    /// and [`Client::try_send`](crate::Client::try_send) fails with [`Error::QueueFull`](crate::Error::QueueFull), but requests to other devices
+    /// aren't held up. Must be at least 1, and defaults to 256.
+    pub queue_capacity: usize,
+
+    /// Upper limit on the number of requests waiting to be sent to all devices together. Requests wait in the client's channel, which holds up to
+    /// this many, and then in the connection's per-device queues, which also hold up to this many, so up to twice this many can be waiting in total.
+    /// This should be well above `queue_capacity`, since once it's reached a backlog for one device does hold up the others. Must be at least 1, and
+    /// defaults to 4096.
+    pub max_queued_requests: usize,
+}
+
+impl Config {
+    // Largest channel or semaphore that tokio can allocate
+    const MAX_QUEUE_CAPACITY: usize = usize::MAX >> 3;
+
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if self.queue_capacity == 0 || self.queue_capacity > Config::MAX_QUEUE_CAPACITY {
+            return Err(Error::InvalidConfig("queue_capacity must be between 1 and usize::MAX >> 3"));
+        }
+        if self.max_queued_requests == 0 || self.max_queued_requests > Config::MAX_QUEUE_CAPACITY {
+            return Err(Error::InvalidConfig("max_queued_requests must be between 1 and usize::MAX >> 3"));
+        }
+        if let Some(ref limit) = self.rate_limit {
+            limit.validate()?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            rate_limit: Some(RateLimit::RECOMMENDED),
+            coalesce: false,
+            queue_capacity: 256,
+            max_queued_requests: 4096,
+        }
+    }
+}
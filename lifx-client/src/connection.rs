@@ -4,13 +4,14 @@ use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use futures::{Sink, Stream};
 use lifx_proto::{Packet, Message, Service};
-use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot, OwnedSemaphorePermit};
 use tokio_util::udp::UdpFramed;
 
 use crate::codec::Codec;
 use crate::error::Error;
+use crate::rate_limit::RateLimiter;
+use crate::transport::Transport;
 use crate::DeviceAddress;
 
 // This is modeled on how tokio-postgres handles client I/O. The benefit of structuring things this way is that we can send messages and read responses
@@ -18,9 +19,11 @@ use crate::DeviceAddress;
 // See https://github.com/sfackler/rust-postgres/blob/77aa702e6c9052cddb256b56c5a8ad30f5272c0a/tokio-postgres/src/connection.rs
 
 pub struct Request {
-    address: DeviceAddress,
-    message: Message,
+    pub(crate) address: DeviceAddress,
+    pub(crate) message: Message,
     response: Option<Response>,
+    // Never read, but holds this request's place in its device's queue until it's sent
+    _permit: Option<OwnedSemaphorePermit>,
 }
 
 #[derive(Debug)]
@@ -28,21 +31,26 @@ pub struct InboundMessage {
     addr: SocketAddr,
     packet: Packet,
 }
-/// Expected response for a message
-pub enum Response {
-    Acknowledgement(oneshot::Sender<()>),
-    Reply(oneshot::Sender<InboundMessage>),
+/// Expected responses for a message. A message may ask for an acknowledgement, a reply, or both, which arrive independently.
+pub struct Response {
+    /// Notified when the acknowledgement arrives. There may be several senders if requests were coalesced, and all of them are notified.
+    acknowledgement: Vec<oneshot::Sender<()>>,
+    reply: Option<oneshot::Sender<InboundMessage>>,
 }
 
 /// Connection to LIFX devices on the local network.
 ///
 /// This is the "backend" half of a LIFX client, which performs network I/O and handles protocol details. It should generally be executed in the background.
-pub struct Connection {
-    socket: UdpFramed<Codec>,
+///
+/// By default, a `Connection` communicates over a UDP socket, but it can run over any [`Transport`].
+pub struct Connection<T = UdpFramed<Codec>> {
+    transport: T,
     source: u32,
 
-    requests: mpsc::UnboundedReceiver<Request>,
+    requests: mpsc::Receiver<Request>,
+    requests_closed: bool,
     pending_request: Option<Request>,
+    rate_limiter: RateLimiter,
 
     sequence_number: u8,
 
@@ -51,18 +59,21 @@ pub struct Connection {
     discovery: broadcast::Sender<DeviceAddress>,
 }
 
-impl Connection {
+impl<T: Transport> Connection<T> {
     pub(crate) fn new(
-        socket: UdpFramed<Codec>,
+        transport: T,
         source: u32,
-        requests: mpsc::UnboundedReceiver<Request>,
+        requests: mpsc::Receiver<Request>,
         discovery: broadcast::Sender<DeviceAddress>,
-    ) -> Connection {
+        rate_limiter: RateLimiter,
+    ) -> Connection<T> {
         Connection {
-            socket,
+            transport,
             source,
             requests,
+            requests_closed: false,
             pending_request: None,
+            rate_limiter,
             sequence_number: 0,
             pending_responses: HashMap::new(),
             discovery,
@@ -76,18 +87,41 @@ impl Connection {
             return Poll::Ready(Some(pending));
         }
 
-        self.requests.poll_recv(cx)
+        // Move everything that's been requested into the rate limiter, which decides what can be sent now
+        while !self.requests_closed && !self.rate_limiter.is_full() {
+            match self.requests.poll_recv(cx) {
+                Poll::Ready(Some(request)) => self.rate_limiter.push(request),
+                Poll::Ready(None) => self.requests_closed = true,
+                Poll::Pending => break,
+            }
+        }
+
+        match self.rate_limiter.poll_next(cx) {
+            Poll::Ready(request) => Poll::Ready(Some(request)),
+            Poll::Pending if self.requests_closed && self.rate_limiter.is_empty() => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
     }
 
-    /// Polls for incoming packets
+    /// Polls for incoming packets. Malformed packets are skipped, since anyone on the network can send them.
     fn poll_incoming(&mut self, cx: &mut Context<'_>) -> Poll<Result<InboundMessage, Error>> {
-        match Pin::new(&mut self.socket).poll_next(cx) {
-            Poll::Ready(None) => Poll::Ready(Err(Error::Network(std::io::Error::new(
-                std::io::ErrorKind::UnexpectedEof,
-                "socket disconnected".to_string(),
-            )))),
-            Poll::Ready(Some(res)) => Poll::Ready(res.map(InboundMessage::from)),
-            Poll::Pending => Poll::Pending,
+        loop {
+            return match Pin::new(&mut self.transport).poll_next(cx) {
+                Poll::Ready(None) => Poll::Ready(Err(Error::Network(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "transport disconnected".to_string(),
+                )))),
+                Poll::Ready(Some(Err(Error::Protocol(err)))) => {
+                    tracing::debug!("Skipping malformed packet: {}", err);
+                    continue;
+                }
+                Poll::Ready(Some(Err(Error::Network(err)))) if err.kind() == std::io::ErrorKind::InvalidData => {
+                    tracing::debug!("Skipping malformed packet: {}", err);
+                    continue;
+                }
+                Poll::Ready(Some(res)) => Poll::Ready(res.map(InboundMessage::from)),
+                Poll::Pending => Poll::Pending,
+            };
         }
     }
 
@@ -101,26 +135,14 @@ impl Connection {
             return;
         }
 
-        match self.pending_responses.remove(&message.packet.sequence()) {
-            Some(response) => match response {
-                Response::Reply(sender) => {
-                    if let Err(m) = sender.send(message) {
-                        tracing::warn!("Dangling response {:?}", m);
-                    }
+        let sequence = message.packet.sequence();
+        match self.pending_responses.get_mut(&sequence) {
+            Some(response) => {
+                response.dispatch(message);
+                if response.is_complete() {
+                    self.pending_responses.remove(&sequence);
                 }
-                Response::Acknowledgement(sender) => {
-                    match message.message() {
-                        Message::Acknowledgement => (),
-                        other => {
-                            tracing::warn!("Received unexpected {:?} message, treating as acknowledgement", other);
-                        }
-                    }
-
-                    if let Err(_) = sender.send(()) {
-                        tracing::warn!("Dangling acknowledgement for {}", message.packet.sequence());
-                    }
-                },
-            },
+            }
             None => {
                 if let Message::StateService(service) = message.packet.message() {
                     let address = DeviceAddress::new(
@@ -130,7 +152,7 @@ impl Connection {
                     match service.service {
                         Service::Udp => {
                             tracing::debug!("Discovered {}", address);
-                            if let Err(_) = self.discovery.send(address) {
+                            if self.discovery.send(address).is_err() {
                                 // TODO: shutdown here?
                                 tracing::warn!("Discovery channel closed");
                             }
@@ -153,10 +175,10 @@ impl Connection {
     /// Poll to send outgoing messages. This will send as many messages as possible, and returns `Ok(true)` if data was written to the socket and it needs to be flushed.
     fn poll_outgoing(&mut self, cx: &mut Context<'_>) -> Result<bool, Error> {
         loop {
-            // First, check if the socket is writable
-            if let Poll::Pending = Pin::new(&mut self.socket)
-                .poll_ready(cx)
-                .map_err(Error::from)?
+            // First, check if the transport is writable
+            if Pin::new(&mut self.transport)
+                .poll_ready(cx)?
+                .is_pending()
             {
                 return Ok(true);
             }
@@ -171,27 +193,25 @@ impl Connection {
             };
 
             // We can only send if the sequence number is available. If too many messages are in flight, we'll have to wait for one to complete.
-            match self.next_sequence(request.response.is_some()) {
+            match self.next_sequence(request.response.is_some(), cx) {
                 Some(sequence) => {
-                    let (response_required, acknowledgement_required) = match request.response {
-                        Some(res) => {
-                            let flags = match res {
-                                Response::Acknowledgement(_) => (false, true),
-                                Response::Reply(_) => (true, false),
-                            };
-                            assert!(
-                                self.pending_responses.insert(sequence, res).is_none(),
-                                "next_sequence returned an in-use sequence number"
-                            );
-                            flags
-                        }
-                        None => (false, false),
-                    };
+                    let mut packet = Packet::builder(request.message)
+                        .with_source(self.source)
+                        .with_target(request.address.target)
+                        .with_sequence(sequence);
+                    if let Some(res) = request.response {
+                        packet = packet
+                            .with_response_required(res.reply.is_some())
+                            .with_acknowledgement_required(!res.acknowledgement.is_empty());
+                        assert!(
+                            self.pending_responses.insert(sequence, res).is_none(),
+                            "next_sequence returned an in-use sequence number"
+                        );
+                    }
 
-                    let packet = Packet::new(self.source, request.address.target, sequence, response_required, acknowledgement_required, request.message);
-                    Pin::new(&mut self.socket)
-                        .start_send((packet, request.address.service_address))
-                        .map_err(Error::from)?;
+                    let packet = packet.build();
+                    Pin::new(&mut self.transport)
+                        .start_send((packet, request.address.service_address))?;
                 }
                 None => {
                     tracing::trace!("Deferring request, too many in flight");
@@ -203,33 +223,42 @@ impl Connection {
     }
 
     fn poll_flush(&mut self, cx: &mut Context<'_>) -> Result<(), Error> {
-        let _ = Pin::new(&mut self.socket)
+        let _ = Pin::new(&mut self.transport)
             .poll_flush(cx)
             .map_err(Error::from)?;
         Ok(())
     }
 
-    fn next_sequence(&mut self, has_response: bool) -> Option<u8> {
+    fn next_sequence(&mut self, has_response: bool, cx: &mut Context<'_>) -> Option<u8> {
         if has_response {
-            // Search through the sequence number space looking for one that doesn't correspond to a pending message
-            // TODO: this could probably wrap around too
-            for seq in self.sequence_number..=std::u8::MAX {
-                if !self.pending_responses.contains_key(&seq) {
-                    self.sequence_number = seq.wrapping_add(1);
-                    return Some(seq);
+            let seq = match self.free_sequence() {
+                Some(seq) => seq,
+                None => {
+                    // Every sequence number is in use, but some of them may belong to requests that no one is waiting on anymore, such as ones
+                    // that timed out. Their replies may never arrive, so give up on them. This also makes sure we're woken up when any other
+                    // request is abandoned, so a deferred request can't wait forever.
+                    self.pending_responses.retain(|_, response| !response.poll_abandoned(cx));
+                    self.free_sequence()?
                 }
-            }
-
-            None
+            };
+            self.sequence_number = seq.wrapping_add(1);
+            Some(seq)
         } else {
             let seq = self.sequence_number;
             self.sequence_number = self.sequence_number.wrapping_add(1);
             Some(seq)
         }
     }
+
+    /// Search through the sequence number space, starting after the last one used, for one that doesn't correspond to a pending message
+    fn free_sequence(&self) -> Option<u8> {
+        (0..=u8::MAX)
+            .map(|offset| self.sequence_number.wrapping_add(offset))
+            .find(|seq| !self.pending_responses.contains_key(seq))
+    }
 }
 
-impl Future for Connection {
+impl<T: Transport> Future for Connection<T> {
     type Output = Result<(), Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
@@ -251,9 +280,104 @@ impl Request {
         Request {
             address,
             message,
-            response
+            response,
+            _permit: None,
         }
     }
+
+    pub(crate) fn with_permit(self, permit: OwnedSemaphorePermit) -> Request {
+        Request { _permit: Some(permit), ..self }
+    }
+
+    /// Whether or not this request can replace `older`, an earlier request that hasn't been sent yet. Only requests that set device state are replaced,
+    /// since sending the latest value is equivalent to sending every intermediate one.
+    pub(crate) fn supersedes(&self, older: &Request) -> bool {
+        let coalescable = matches!(
+            (&self.message, &older.message),
+            (Message::SetColor(_), Message::SetColor(_))
+        );
+
+        // Requests waiting on a reply expect to see the result of their own message
+        let replies = self.expects_reply() || older.expects_reply();
+
+        coalescable && !replies && self.address == older.address
+    }
+
+    /// Take over the response of `older`, a request which this one supersedes. Anyone waiting for `older` to be acknowledged will be notified when
+    /// this request is.
+    pub(crate) fn absorb(&mut self, older: Request) {
+        if let Some(older) = older.response {
+            let response = self.response.get_or_insert_with(Response::none);
+            response.acknowledgement.extend(older.acknowledgement);
+        }
+    }
+
+    fn expects_reply(&self) -> bool {
+        matches!(self.response, Some(Response { reply: Some(_), .. }))
+    }
+}
+
+impl Response {
+    fn none() -> Response {
+        Response {
+            acknowledgement: Vec::new(),
+            reply: None,
+        }
+    }
+
+    pub fn acknowledgement(sender: oneshot::Sender<()>) -> Response {
+        Response {
+            acknowledgement: vec![sender],
+            reply: None,
+        }
+    }
+
+    pub fn reply(sender: oneshot::Sender<InboundMessage>) -> Response {
+        Response {
+            acknowledgement: Vec::new(),
+            reply: Some(sender),
+        }
+    }
+
+    pub fn acknowledgement_and_reply(acknowledgement: oneshot::Sender<()>, reply: oneshot::Sender<InboundMessage>) -> Response {
+        Response {
+            acknowledgement: vec![acknowledgement],
+            reply: Some(reply),
+        }
+    }
+
+    /// Pass a message received in response to the request along to whoever is waiting for it. Acknowledgements and replies are told apart by message
+    /// type, so a duplicated reply can never stand in for the acknowledgement, or the other way around.
+    fn dispatch(&mut self, message: InboundMessage) {
+        if matches!(message.message(), Message::Acknowledgement) {
+            if self.acknowledgement.is_empty() {
+                tracing::trace!("Duplicate or unexpected acknowledgement for {}", message.packet.sequence());
+            }
+            for sender in self.acknowledgement.drain(..) {
+                if sender.send(()).is_err() {
+                    tracing::warn!("Dangling acknowledgement for {}", message.packet.sequence());
+                }
+            }
+        } else if let Some(sender) = self.reply.take() {
+            if let Err(m) = sender.send(message) {
+                tracing::warn!("Dangling response {:?}", m);
+            }
+        } else {
+            tracing::trace!("Duplicate or unexpected response {:?}", message);
+        }
+    }
+
+    /// Whether or not everyone waiting for this response has given up, for example by dropping the future from
+    /// [`Client::send_with_response`](crate::Client::send_with_response). If not, `cx` is woken up once they do.
+    fn poll_abandoned(&mut self, cx: &mut Context<'_>) -> bool {
+        self.acknowledgement.iter_mut().all(|sender| sender.poll_closed(cx).is_ready())
+            && self.reply.iter_mut().all(|sender| sender.poll_closed(cx).is_ready())
+    }
+
+    /// Whether or not every expected response has arrived
+    fn is_complete(&self) -> bool {
+        self.acknowledgement.is_empty() && self.reply.is_none()
+    }
 }
 
 impl InboundMessage {
@@ -264,7 +388,7 @@ impl InboundMessage {
     }
 
     pub fn message(&self) -> &Message {
-        &self.packet.message()
+        self.packet.message()
     }
 
     pub fn packet(&self) -> &Packet {
@@ -280,4 +404,38 @@ impl From<(Packet, SocketAddr)> for InboundMessage {
     fn from((packet, addr): (Packet, SocketAddr)) -> InboundMessage {
         InboundMessage::new(packet, addr)
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_dispatch() {
+    use lifx_proto::label::Label;
+    use lifx_proto::message::StateLabel;
+    use tokio::sync::oneshot::error::TryRecvError;
+
+    let addr: SocketAddr = "127.0.0.1:56700".parse().unwrap();
+    let inbound = |message: Message| InboundMessage::new(Packet::builder(message).with_sequence(7).build(), addr);
+    let reply = || Message::StateLabel(StateLabel { label: Label::new("Kitchen") });
+
+    let (ack_tx, mut ack_rx) = oneshot::channel();
+    let (reply_tx, mut reply_rx) = oneshot::channel();
+    let mut response = Response::acknowledgement_and_reply(ack_tx, reply_tx);
+
+    // The reply overtakes the acknowledgement, and is then duplicated
+    response.dispatch(inbound(reply()));
+    assert_eq!(reply_rx.try_recv().unwrap().into_message(), reply());
+    response.dispatch(inbound(reply()));
+    assert!(matches!(ack_rx.try_recv(), Err(TryRecvError::Empty)));
+    assert!(!response.is_complete());
+
+    response.dispatch(inbound(Message::Acknowledgement));
+    assert_eq!(ack_rx.try_recv(), Ok(()));
+    assert!(response.is_complete());
+
+    // Replies never complete a request that only wants an acknowledgement
+    let (ack_tx, mut ack_rx) = oneshot::channel();
+    let mut response = Response::acknowledgement(ack_tx);
+    response.dispatch(inbound(reply()));
+    assert!(matches!(ack_rx.try_recv(), Err(TryRecvError::Empty)));
+    response.dispatch(inbound(Message::Acknowledgement));
+    assert_eq!(ack_rx.try_recv(), Ok(()));
+}
@@ -0,0 +1,182 @@
+//! Helpers for discovering devices on networks where a single global broadcast doesn't reach every device
+
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::error::Error as ClientError;
+
+/// A local IPv4 network interface, which can be used for directed broadcast discovery.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Interface {
+    name: String,
+    address: Ipv4Addr,
+    subnet: Ipv4Cidr,
+    broadcast: Ipv4Addr,
+}
+
+impl Interface {
+    /// The operating system's name for this interface, such as `eth0`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// This host's address on the interface
+    pub fn address(&self) -> Ipv4Addr {
+        self.address
+    }
+
+    /// The subnet the interface is attached to
+    pub fn subnet(&self) -> Ipv4Cidr {
+        self.subnet
+    }
+
+    /// The directed broadcast address for the interface's subnet (for example, `192.168.1.255`)
+    pub fn broadcast(&self) -> Ipv4Addr {
+        self.broadcast
+    }
+}
+
+/// List the local IPv4 interfaces that discovery messages can be broadcast on. Loopback interfaces are skipped.
+pub fn interfaces() -> Result<Vec<Interface>, ClientError> {
+    let interfaces = if_addrs::get_if_addrs()?
+        .into_iter()
+        .filter(|iface| !iface.is_loopback())
+        .filter_map(|iface| match iface.addr {
+            if_addrs::IfAddr::V4(addr) => {
+                let prefix_len = u32::from(addr.netmask).leading_ones() as u8;
+                let subnet = Ipv4Cidr::new(addr.ip, prefix_len).ok()?;
+                Some(Interface {
+                    name: iface.name,
+                    address: addr.ip,
+                    subnet,
+                    broadcast: addr.broadcast.unwrap_or_else(|| subnet.broadcast()),
+                })
+            }
+            if_addrs::IfAddr::V6(_) => None,
+        })
+        .collect();
+    Ok(interfaces)
+}
+
+/// A range of IPv4 addresses, written in CIDR notation (for example, `192.168.1.0/24`).
+///
+/// This is used to scan a subnet for devices one address at a time, for networks that filter broadcast traffic.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Ipv4Cidr {
+    network: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Ipv4Cidr {
+    /// Create a new range from an address in it and the length of its network prefix. Any host bits set in `address` are cleared.
+    pub fn new(address: Ipv4Addr, prefix_len: u8) -> Result<Ipv4Cidr, CidrError> {
+        if prefix_len > 32 {
+            return Err(CidrError(format!("{}/{}", address, prefix_len)));
+        }
+
+        let network = Ipv4Addr::from(u32::from(address) & Ipv4Cidr::mask(prefix_len));
+        Ok(Ipv4Cidr { network, prefix_len })
+    }
+
+    /// The network address of this range
+    pub fn network(&self) -> Ipv4Addr {
+        self.network
+    }
+
+    /// The length of the network prefix, in bits
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// The broadcast address of this range
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network) | !Ipv4Cidr::mask(self.prefix_len))
+    }
+
+    /// Whether or not `address` is in this range
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        u32::from(address) & Ipv4Cidr::mask(self.prefix_len) == u32::from(self.network)
+    }
+
+    /// Iterate over the usable host addresses in this range. The network and broadcast addresses are skipped, except for `/31` and `/32` ranges, which
+    /// don't have them.
+    pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let first = u32::from(self.network);
+        let last = u32::from(self.broadcast());
+        let (first, last) = if self.prefix_len >= 31 {
+            (first, last)
+        } else {
+            (first + 1, last - 1)
+        };
+        (first..=last).map(Ipv4Addr::from)
+    }
+
+    fn mask(prefix_len: u8) -> u32 {
+        u32::MAX.checked_shl(32 - u32::from(prefix_len)).unwrap_or(0)
+    }
+}
+
+impl FromStr for Ipv4Cidr {
+    type Err = CidrError;
+
+    fn from_str(s: &str) -> Result<Ipv4Cidr, CidrError> {
+        let err = || CidrError(s.to_string());
+        let (address, prefix_len) = match s.find('/') {
+            Some(idx) => (&s[..idx], s[idx + 1..].parse().map_err(|_| err())?),
+            None => (s, 32),
+        };
+        let address = address.parse().map_err(|_| err())?;
+        Ipv4Cidr::new(address, prefix_len).map_err(|_| err())
+    }
+}
+
+impl fmt::Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Ranges are serialized in CIDR notation
+#[cfg(feature = "serde")]
+impl serde::Serialize for Ipv4Cidr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Ipv4Cidr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Ipv4Cidr, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("invalid CIDR range: {0}")]
+pub struct CidrError(String);
+
+#[test]
+fn test_cidr() {
+    let cidr: Ipv4Cidr = "192.168.1.17/24".parse().unwrap();
+    assert_eq!(cidr.network(), Ipv4Addr::new(192, 168, 1, 0));
+    assert_eq!(cidr.broadcast(), Ipv4Addr::new(192, 168, 1, 255));
+    assert!(cidr.contains(Ipv4Addr::new(192, 168, 1, 200)));
+    assert!(!cidr.contains(Ipv4Addr::new(192, 168, 2, 1)));
+
+    let hosts: Vec<_> = cidr.hosts().collect();
+    assert_eq!(hosts.len(), 254);
+    assert_eq!(hosts[0], Ipv4Addr::new(192, 168, 1, 1));
+    assert_eq!(hosts[253], Ipv4Addr::new(192, 168, 1, 254));
+
+    let single: Ipv4Cidr = "10.0.0.5".parse().unwrap();
+    assert_eq!(single.hosts().collect::<Vec<_>>(), vec![Ipv4Addr::new(10, 0, 0, 5)]);
+
+    assert!("10.0.0.0/33".parse::<Ipv4Cidr>().is_err());
+    assert!("10.0.0/8".parse::<Ipv4Cidr>().is_err());
+    assert_eq!("0.0.0.0/0".parse::<Ipv4Cidr>().unwrap().broadcast(), Ipv4Addr::BROADCAST);
+}
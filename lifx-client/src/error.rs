@@ -10,4 +10,10 @@ pub enum Error {
 
     #[error("connection closed")]
     ConnectionClosed,
+
+    #[error("request queue is full")]
+    QueueFull,
+
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(&'static str),
 }
@@ -3,22 +3,33 @@ use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 use lifx_proto::DeviceTarget;
 
+pub mod animation;
 mod client;
 mod codec;
+mod config;
 mod connection;
+pub mod discovery;
 mod error;
+pub mod rate_limit;
+pub mod transport;
 
 pub use client::Client;
+pub use config::Config;
 pub use connection::Connection;
+pub use error::Error;
 
 /// Address of a LIFX device. This includes both the UDP socket address and the MAC address-based target filter.
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceAddress {
     service_address: SocketAddr,
     target: DeviceTarget,
 }
 
 impl DeviceAddress {
+    /// Port that LIFX devices listen on for discovery messages
+    pub const DISCOVERY_PORT: u16 = 56700;
+
     pub const fn new(service_address: SocketAddr, target: DeviceTarget) -> DeviceAddress {
         DeviceAddress {
             service_address,
@@ -27,7 +38,12 @@ impl DeviceAddress {
     }
 
     pub fn all() -> DeviceAddress {
-        let udp_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255)), 56700);
+        DeviceAddress::all_at(IpAddr::V4(Ipv4Addr::BROADCAST))
+    }
+
+    /// Address of all devices listening at `ip`. Unlike [`DeviceAddress::all`], this may be a unicast address, for networks that filter broadcast traffic.
+    pub fn all_at(ip: IpAddr) -> DeviceAddress {
+        let udp_address = SocketAddr::new(ip, DeviceAddress::DISCOVERY_PORT);
         DeviceAddress::new(udp_address, DeviceTarget::All)
     }
 }
@@ -37,3 +53,12 @@ impl fmt::Display for DeviceAddress {
         write!(f, "{}@{}", self.target, self.service_address)
     }
 }
+
+#[cfg(all(test, feature = "serde"))]
+#[test]
+fn test_serde() {
+    let address = DeviceAddress::new("192.168.1.20:56700".parse().unwrap(), DeviceTarget::Targeted([0xd0, 0x73, 0xd5, 0, 0, 1].into()));
+    let expected = serde_json::json!({ "service_address": "192.168.1.20:56700", "target": "d0:73:d5:00:00:01" });
+    assert_eq!(serde_json::to_value(address).unwrap(), expected);
+    assert_eq!(serde_json::from_value::<DeviceAddress>(expected).unwrap(), address);
+}
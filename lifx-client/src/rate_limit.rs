@@ -0,0 +1,518 @@
+//! Per-device rate limiting for outgoing messages.
+//!
+//! LIFX devices can only handle about 20 messages per second, and will drop packets if sent more than that. See the
+//! [LIFX documentation](https://lan.developer.lifx.com/docs/communicating-with-device) for details.
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Instant, Sleep};
+
+use crate::connection::Request;
+use crate::{Config, Error};
+#[cfg(test)]
+use crate::{connection::Response, DeviceAddress};
+#[cfg(test)]
+use lifx_proto::Message;
+
+/// Limit on how quickly messages are sent to a single device, as a token bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    per_second: f64,
+    burst: u32,
+}
+
+impl RateLimit {
+    /// The rate LIFX recommends not exceeding for a single device
+    pub const RECOMMENDED: RateLimit = RateLimit { per_second: 20.0, burst: 1 };
+
+    /// Limit each device to `per_second` messages per second, evenly spaced. The rate must be positive, finite and high enough that the time between
+    /// messages fits in a [`Duration`], or creating a client with this limit fails with [`Error::InvalidConfig`](crate::Error::InvalidConfig).
+    pub fn per_second(per_second: f64) -> RateLimit {
+        RateLimit { per_second, burst: 1 }
+    }
+
+    /// Allow up to `burst` messages to be sent back-to-back to a device that hasn't received any recently. The burst must be at least 1, or
+    /// creating a client with this limit fails with [`Error::InvalidConfig`](crate::Error::InvalidConfig).
+    pub fn with_burst(self, burst: u32) -> RateLimit {
+        RateLimit { burst, ..self }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        // The interval is negative, infinite or NaN for rates that are non-positive or NaN, and too large for very small rates
+        if !self.per_second.is_finite() || Duration::try_from_secs_f64(self.per_second.recip()).is_err() {
+            return Err(Error::InvalidConfig("rate limit must be a finite number of messages per second, above about 5e-20"));
+        }
+        if self.burst == 0 {
+            return Err(Error::InvalidConfig("rate limit burst must be at least 1"));
+        }
+        Ok(())
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.per_second)
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> RateLimit {
+        RateLimit::RECOMMENDED
+    }
+}
+
+/// Statistics on how long requests have waited for the rate limit, and how many were coalesced. This is a handle that can be cloned and read while the [`Connection`](crate::Connection)
+/// is running.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitMetrics(Arc<MetricsInner>);
+
+#[derive(Debug, Default)]
+struct MetricsInner {
+    requests: AtomicU64,
+    delayed_requests: AtomicU64,
+    total_wait_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+    coalesced_requests: AtomicU64,
+}
+
+impl RateLimitMetrics {
+    /// Total number of requests that have been sent
+    pub fn requests(&self) -> u64 {
+        self.0.requests.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests that had to wait for their device's rate limit before being sent
+    pub fn delayed_requests(&self) -> u64 {
+        self.0.delayed_requests.load(Ordering::Relaxed)
+    }
+
+    /// Total time requests have spent waiting for the rate limit
+    pub fn total_wait(&self) -> Duration {
+        Duration::from_micros(self.0.total_wait_micros.load(Ordering::Relaxed))
+    }
+
+    /// Longest time any single request has waited for the rate limit
+    pub fn max_wait(&self) -> Duration {
+        Duration::from_micros(self.0.max_wait_micros.load(Ordering::Relaxed))
+    }
+
+    /// Number of requests that were replaced by a newer request before being sent, if coalescing is enabled
+    pub fn coalesced_requests(&self) -> u64 {
+        self.0.coalesced_requests.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, wait: Duration, delayed: bool) {
+        let micros = wait.as_micros() as u64;
+        self.0.requests.fetch_add(1, Ordering::Relaxed);
+        if delayed {
+            self.0.delayed_requests.fetch_add(1, Ordering::Relaxed);
+        }
+        self.0.total_wait_micros.fetch_add(micros, Ordering::Relaxed);
+        self.0.max_wait_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+}
+
+/// Token bucket tracking how many messages may currently be sent to a device.
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit, now: Instant) -> TokenBucket {
+        TokenBucket { tokens: limit.burst as f64, last_refill: now }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.last_refill = now;
+    }
+
+    /// Try to take a token, returning the time at which one will be available if there isn't one now.
+    fn try_take(&mut self, limit: &RateLimit, now: Instant) -> Result<(), Instant> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(now + limit.interval().mul_f64(1.0 - self.tokens))
+        }
+    }
+
+    fn is_full(&self, limit: &RateLimit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens + elapsed * limit.per_second >= limit.burst as f64
+    }
+}
+
+struct Queued {
+    request: Request,
+    enqueued_at: Instant,
+    delayed: bool,
+}
+
+struct DeviceQueue {
+    bucket: TokenBucket,
+    requests: VecDeque<Queued>,
+}
+
+/// Limits how many requests can be waiting for each device. A client takes a permit for a request's device before putting it in the channel to the
+/// connection, and the permit is released once the request has been sent, or replaced by coalescing. Since only requests with a permit reach the
+/// channel, a device with a long backlog can't fill the channel and hold up requests to other devices.
+#[derive(Debug, Clone)]
+pub(crate) struct QueuePermits(Arc<Mutex<PermitsInner>>);
+
+#[derive(Debug)]
+struct PermitsInner {
+    capacity: usize,
+    devices: HashMap<SocketAddr, Arc<Semaphore>>,
+    // Size at which to drop devices that have nothing queued, so scanning many addresses doesn't grow the map forever
+    prune_at: usize,
+}
+
+impl QueuePermits {
+    pub(crate) fn new(capacity: usize) -> QueuePermits {
+        QueuePermits(Arc::new(Mutex::new(PermitsInner { capacity, devices: HashMap::new(), prune_at: 64 })))
+    }
+
+    /// Wait until there's room in the queue for `address`
+    pub(crate) async fn acquire(&self, address: SocketAddr) -> OwnedSemaphorePermit {
+        self.semaphore(address).acquire_owned().await.expect("queue semaphores are never closed")
+    }
+
+    /// Take a place in the queue for `address`, or return `None` if it's full
+    pub(crate) fn try_acquire(&self, address: SocketAddr) -> Option<OwnedSemaphorePermit> {
+        self.semaphore(address).try_acquire_owned().ok()
+    }
+
+    fn semaphore(&self, address: SocketAddr) -> Arc<Semaphore> {
+        let mut inner = self.0.lock().unwrap();
+        if inner.devices.len() >= inner.prune_at {
+            // Outstanding permits and waiters hold a reference, so the map's is the only one for devices with nothing queued
+            inner.devices.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+            inner.prune_at = (inner.devices.len() * 2).max(64);
+        }
+        let capacity = inner.capacity;
+        inner.devices.entry(address).or_insert_with(|| Arc::new(Semaphore::new(capacity))).clone()
+    }
+}
+
+/// Queues requests per device, releasing them as each device's rate limit allows. Requests for one device are always sent in order, but a device that
+/// is being rate-limited doesn't hold up requests for other devices. Devices that are ready at the same time take turns, so a device with a long
+/// backlog can't starve the others.
+///
+/// If coalescing is enabled, a request that sets device state replaces an equivalent request at the back of its device's queue instead of waiting
+/// behind it. Only the last request is replaced, so coalescing never reorders requests to a device.
+pub(crate) struct RateLimiter {
+    limit: Option<RateLimit>,
+    coalesce: bool,
+    capacity: usize,
+    devices: HashMap<SocketAddr, DeviceQueue>,
+    // Every device in `devices`, in the order they get a turn. A device moves to the back after sending a request.
+    order: VecDeque<SocketAddr>,
+    len: usize,
+    timer: Option<Pin<Box<Sleep>>>,
+    metrics: RateLimitMetrics,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: &Config, metrics: RateLimitMetrics) -> RateLimiter {
+        RateLimiter {
+            limit: config.rate_limit,
+            coalesce: config.coalesce,
+            capacity: config.max_queued_requests,
+            devices: HashMap::new(),
+            order: VecDeque::new(),
+            len: 0,
+            timer: None,
+            metrics,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether or not the queues are at their combined capacity. New requests should be left in the client's channel until there's room, so that
+    /// clients see backpressure. Each device's own capacity is enforced by [`QueuePermits`] before requests reach the channel.
+    pub(crate) fn is_full(&self) -> bool {
+        self.len >= self.capacity
+    }
+
+    /// Add a request to the back of its device's queue, or in place of the request at the back if coalescing is enabled and this one supersedes it
+    pub(crate) fn push(&mut self, request: Request) {
+        let now = Instant::now();
+        let limit = self.limit.unwrap_or(RateLimit::RECOMMENDED);
+        let queue = match self.devices.entry(request.address.service_address) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                self.order.push_back(*entry.key());
+                entry.insert(DeviceQueue { bucket: TokenBucket::new(&limit, now), requests: VecDeque::new() })
+            }
+        };
+
+        if self.coalesce {
+            // Replacing a request further up the queue would move this one ahead of anything queued after it
+            if let Some(queued) = queue.requests.back_mut().filter(|queued| request.supersedes(&queued.request)) {
+                tracing::trace!("Coalescing {:?} request to {}", request.message.message_type(), request.address);
+                let older = std::mem::replace(&mut queued.request, request);
+                queued.request.absorb(older);
+                self.metrics.0.coalesced_requests.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+
+        queue.requests.push_back(Queued { request, enqueued_at: now, delayed: false });
+        self.len += 1;
+    }
+
+    /// Polls for the next request that can be sent without exceeding its device's rate limit. If every queued request is being held back, this
+    /// arranges for the task to be woken once one can be sent.
+    pub(crate) fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Request> {
+        loop {
+            let now = Instant::now();
+            let mut next_ready: Option<Instant> = None;
+            let mut ready = None;
+
+            for (idx, address) in self.order.iter().enumerate() {
+                let queue = self.devices.get_mut(address).expect("every device in the order has a queue");
+                if queue.requests.is_empty() {
+                    continue;
+                }
+
+                let taken = match self.limit {
+                    Some(ref limit) => queue.bucket.try_take(limit, now),
+                    None => Ok(()),
+                };
+                match taken {
+                    Ok(()) => {
+                        ready = Some(idx);
+                        break;
+                    }
+                    Err(at) => {
+                        // Anything still queued at this point has to wait for a token
+                        for queued in queue.requests.iter_mut() {
+                            queued.delayed = true;
+                        }
+                        next_ready = Some(next_ready.map_or(at, |next| next.min(at)));
+                    }
+                }
+            }
+
+            if let Some(idx) = ready {
+                // Go to the back of the line, so every other ready device gets a turn first
+                let address = self.order.remove(idx).expect("ready device is in the order");
+                self.order.push_back(address);
+                let queue = self.devices.get_mut(&address).expect("ready device has a queue");
+                let queued = queue.requests.pop_front().expect("ready device has a queued request");
+                self.len -= 1;
+                self.prune(now);
+
+                let wait = now.saturating_duration_since(queued.enqueued_at);
+                if queued.delayed {
+                    tracing::trace!("Request to {} was rate-limited for {:?}", address, wait);
+                }
+                self.metrics.record(wait, queued.delayed);
+                return Poll::Ready(queued.request);
+            }
+
+            let deadline = match next_ready {
+                Some(deadline) => deadline,
+                None => {
+                    self.timer = None;
+                    return Poll::Pending;
+                }
+            };
+
+            let timer = match self.timer {
+                Some(ref mut timer) => {
+                    timer.as_mut().reset(deadline);
+                    timer
+                }
+                None => self.timer.insert(Box::pin(tokio::time::sleep_until(deadline))),
+            };
+            if timer.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            // The deadline already passed, so try again
+        }
+    }
+
+    /// Forget devices that have nothing queued and a full bucket, since they're indistinguishable from devices we've never seen
+    fn prune(&mut self, now: Instant) {
+        let limit = self.limit;
+        self.devices.retain(|_, queue| {
+            !queue.requests.is_empty() || limit.is_some_and(|limit| !queue.bucket.is_full(&limit, now))
+        });
+        let devices = &self.devices;
+        self.order.retain(|address| devices.contains_key(address));
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    let limit = RateLimit::per_second(10.0).with_burst(2);
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(&limit, start);
+
+    // The initial burst is available immediately
+    assert!(bucket.try_take(&limit, start).is_ok());
+    assert!(bucket.try_take(&limit, start).is_ok());
+
+    // After that, a token is available every 100ms
+    let next = bucket.try_take(&limit, start).unwrap_err();
+    assert_eq!(next, start + Duration::from_millis(100));
+    assert!(bucket.try_take(&limit, start + Duration::from_millis(50)).is_err());
+    assert!(bucket.try_take(&limit, start + Duration::from_millis(100)).is_ok());
+
+    // Tokens don't accumulate past the burst size
+    let later = start + Duration::from_secs(10);
+    assert!(bucket.is_full(&limit, later));
+    assert!(bucket.try_take(&limit, later).is_ok());
+    assert!(bucket.try_take(&limit, later).is_ok());
+    assert!(bucket.try_take(&limit, later).is_err());
+}
+
+#[test]
+fn test_invalid_rate_limit() {
+    let config = |limit| Config { rate_limit: Some(limit), ..Config::default() };
+    assert!(config(RateLimit::RECOMMENDED).validate().is_ok());
+    assert!(config(RateLimit::per_second(1e-3).with_burst(5)).validate().is_ok());
+
+    for limit in [RateLimit::per_second(0.0), RateLimit::per_second(-1.0), RateLimit::per_second(f64::NAN), RateLimit::per_second(f64::INFINITY), RateLimit::per_second(1e-20)] {
+        assert!(matches!(config(limit).validate(), Err(Error::InvalidConfig(_))));
+    }
+    assert!(matches!(config(RateLimit::RECOMMENDED.with_burst(0)).validate(), Err(Error::InvalidConfig(_))));
+}
+
+#[cfg(test)]
+fn request(id: u8, message: Message, response: Option<Response>) -> Request {
+    use lifx_proto::DeviceTarget;
+
+    let address = DeviceAddress::new(SocketAddr::from(([127, 0, 0, id], 56700)), DeviceTarget::All);
+    Request::new(address, message, response)
+}
+
+#[cfg(test)]
+fn set_color(hue: u16) -> Message {
+    use lifx_proto::color::{Hsbk, Kelvin};
+    use lifx_proto::duration::ProtocolDuration;
+    use lifx_proto::message::SetColor;
+
+    let color = Hsbk { hue, saturation: 0, brightness: 0, temperature: Kelvin::new(3500) };
+    Message::SetColor(SetColor { color, duration: ProtocolDuration::ZERO })
+}
+
+#[tokio::test]
+async fn test_coalesce() {
+    use futures::future::poll_fn;
+    use tokio::sync::oneshot;
+
+    let config = Config { rate_limit: None, coalesce: true, ..Config::default() };
+    let metrics = RateLimitMetrics::default();
+    let mut limiter = RateLimiter::new(&config, metrics.clone());
+
+    let (older_tx, mut older_rx) = oneshot::channel();
+    limiter.push(request(1, set_color(1), Some(Response::acknowledgement(older_tx))));
+    limiter.push(request(1, set_color(2), None));
+    assert_eq!(metrics.coalesced_requests(), 1);
+
+    // Only the newest color is sent, and it carries the older request's acknowledgement
+    let sent = poll_fn(|cx| limiter.poll_next(cx)).await;
+    assert_eq!(sent.message, set_color(2));
+    assert!(limiter.is_empty());
+    assert_eq!(older_rx.try_recv(), Err(oneshot::error::TryRecvError::Empty));
+    drop(sent);
+    assert_eq!(older_rx.try_recv(), Err(oneshot::error::TryRecvError::Closed));
+
+    // Requests to other devices aren't coalesced
+    limiter.push(request(1, set_color(3), None));
+    limiter.push(request(2, set_color(4), None));
+    assert_eq!(metrics.coalesced_requests(), 1);
+}
+
+#[tokio::test]
+async fn test_coalesce_preserves_order() {
+    use futures::future::poll_fn;
+    use lifx_proto::label::Label;
+    use lifx_proto::message::SetLabel;
+
+    let config = Config { rate_limit: None, coalesce: true, ..Config::default() };
+    let metrics = RateLimitMetrics::default();
+    let mut limiter = RateLimiter::new(&config, metrics.clone());
+
+    // The second color can't replace the first without jumping ahead of the label change
+    let set_label = Message::SetLabel(SetLabel { label: Label::new("Kitchen") });
+    limiter.push(request(1, set_color(1), None));
+    limiter.push(request(1, set_label.clone(), None));
+    limiter.push(request(1, set_color(2), None));
+    limiter.push(request(1, set_color(3), None));
+    assert_eq!(metrics.coalesced_requests(), 1);
+
+    let mut sent = Vec::new();
+    while !limiter.is_empty() {
+        sent.push(poll_fn(|cx| limiter.poll_next(cx)).await.message);
+    }
+    assert_eq!(sent, vec![set_color(1), set_label, set_color(3)]);
+}
+
+#[tokio::test]
+async fn test_round_robin() {
+    use futures::future::poll_fn;
+
+    let config = Config { rate_limit: None, ..Config::default() };
+    let mut limiter = RateLimiter::new(&config, RateLimitMetrics::default());
+
+    // Device 1 has a backlog before device 2 queues anything, but they still take turns
+    for hue in 0..4 {
+        limiter.push(request(1, set_color(hue), None));
+    }
+    for hue in 10..12 {
+        limiter.push(request(2, set_color(hue), None));
+    }
+
+    let mut sent = Vec::new();
+    while !limiter.is_empty() {
+        sent.push(poll_fn(|cx| limiter.poll_next(cx)).await.message);
+    }
+    let expected: Vec<Message> = [0, 10, 1, 11, 2, 3].iter().map(|hue| set_color(*hue)).collect();
+    assert_eq!(sent, expected);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_rate_limit_metrics() {
+    use futures::future::poll_fn;
+
+    let config = Config { rate_limit: Some(RateLimit::per_second(10.0)), ..Config::default() };
+    let metrics = RateLimitMetrics::default();
+    let mut limiter = RateLimiter::new(&config, metrics.clone());
+    let start = Instant::now();
+    for id in 1..=2 {
+        limiter.push(request(id, set_color(0), None));
+        limiter.push(request(id, set_color(1), None));
+    }
+
+    // Each device sends one request immediately, and its second once its bucket refills 100ms later. Neither waits for the other.
+    let mut sent = Vec::new();
+    while !limiter.is_empty() {
+        let request = poll_fn(|cx| limiter.poll_next(cx)).await;
+        sent.push((request.address.service_address.ip(), start.elapsed()));
+    }
+    let device = |id: u8| std::net::IpAddr::from([127, 0, 0, id]);
+    let interval = Duration::from_millis(100);
+    assert_eq!(sent, vec![(device(1), Duration::ZERO), (device(2), Duration::ZERO), (device(1), interval), (device(2), interval)]);
+
+    assert_eq!(metrics.requests(), 4);
+    assert_eq!(metrics.delayed_requests(), 2);
+    assert_eq!(metrics.max_wait(), interval);
+    assert_eq!(metrics.total_wait(), interval * 2);
+}
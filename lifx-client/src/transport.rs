@@ -0,0 +1,112 @@
+//! Transports that a [`Connection`](crate::Connection) can send and receive packets over.
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::{Sink, Stream};
+use lifx_proto::Packet;
+use tokio::sync::mpsc;
+
+use crate::error::Error;
+
+/// A packet-oriented transport, which sends and receives LIFX packets tagged with the address of the remote peer.
+///
+/// This is implemented for any type that is both a `Sink` and `Stream` of `(Packet, SocketAddr)` pairs, such as a `UdpFramed` socket, which is what
+/// [`Client`](crate::Client) uses by default.
+pub trait Transport:
+    Sink<(Packet, SocketAddr), Error = Error> + Stream<Item = Result<(Packet, SocketAddr), Error>> + Unpin
+{
+}
+
+impl<T> Transport for T where
+    T: Sink<(Packet, SocketAddr), Error = Error> + Stream<Item = Result<(Packet, SocketAddr), Error>> + Unpin
+{
+}
+
+/// An in-memory [`Transport`], which is connected to a single peer. This is mostly useful for testing.
+///
+/// Every packet sent on one end of the pair is received by the other, no matter what address it was sent to, so broadcast discovery messages are seen
+/// by the peer too. Received packets are tagged with the address of the end that sent them.
+#[derive(Debug)]
+pub struct ChannelTransport {
+    local_addr: SocketAddr,
+    tx: mpsc::UnboundedSender<(Packet, SocketAddr)>,
+    rx: mpsc::UnboundedReceiver<(Packet, SocketAddr)>,
+}
+
+impl ChannelTransport {
+    /// Create a connected pair of transports, which appear to each other to have the addresses `a` and `b`
+    pub fn pair(a: SocketAddr, b: SocketAddr) -> (ChannelTransport, ChannelTransport) {
+        let (a_tx, b_rx) = mpsc::unbounded_channel();
+        let (b_tx, a_rx) = mpsc::unbounded_channel();
+        let a = ChannelTransport { local_addr: a, tx: a_tx, rx: a_rx };
+        let b = ChannelTransport { local_addr: b, tx: b_tx, rx: b_rx };
+        (a, b)
+    }
+
+    /// The address that this end of the pair appears to have
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Sink<(Packet, SocketAddr)> for ChannelTransport {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, (packet, _): (Packet, SocketAddr)) -> Result<(), Error> {
+        let local_addr = self.local_addr;
+        self.tx.send((packet, local_addr)).map_err(|_| Error::ConnectionClosed)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for ChannelTransport {
+    type Item = Result<(Packet, SocketAddr), Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx).map(|item| item.map(Ok))
+    }
+}
+
+#[tokio::test]
+async fn test_channel_transport() {
+    use futures::{SinkExt, StreamExt};
+    use lifx_proto::message::StateLabel;
+    use lifx_proto::{label::Label, DeviceTarget, Message};
+
+    use crate::{Client, Config, DeviceAddress};
+
+    let client_addr: SocketAddr = "10.0.0.1:56700".parse().unwrap();
+    let device_addr: SocketAddr = "10.0.0.2:56700".parse().unwrap();
+    let (client_transport, mut device) = ChannelTransport::pair(client_addr, device_addr);
+
+    let (mut client, conn) = Client::with_transport(client_transport, 1234, Config::default()).unwrap();
+    tokio::spawn(conn);
+
+    let target = DeviceTarget::Targeted([0xd0, 0x73, 0xd5, 0x00, 0x00, 0x01].into());
+    let responder = tokio::spawn(async move {
+        let (request, from) = device.next().await.unwrap().unwrap();
+        assert_eq!(from, client_addr);
+        assert_eq!(request.message(), &Message::GetLabel);
+
+        let reply = Message::StateLabel(StateLabel { label: Label::new("Kitchen") });
+        let reply = Packet::new(request.source(), target, request.sequence(), false, false, reply);
+        device.send((reply, from)).await.unwrap();
+    });
+
+    let label = client.get_label(DeviceAddress::new(device_addr, target)).await.unwrap();
+    assert_eq!(label, "Kitchen");
+    responder.await.unwrap();
+}
@@ -0,0 +1,456 @@
+//! End-to-end tests of the client against simulated devices
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::future::join_all;
+use lifx_client::animation::{Animation, Keyframe, Track};
+use lifx_client::rate_limit::RateLimit;
+use lifx_client::{Client, Config, DeviceAddress, Error};
+use lifx_proto::color::{Hsbk, Kelvin};
+use lifx_proto::label::Label;
+use lifx_proto::duration::ProtocolDuration;
+use lifx_proto::message::{SetColor, SetLabel, StateLabel};
+use lifx_proto::products::{FirmwareVersion, KelvinPolicy};
+use lifx_proto::{DeviceTarget, Message, ProtocolError};
+use lifx_sim::{Faults, Latency, Simulator, VirtualDevice};
+use macaddr::MacAddr6;
+use tokio::time::{timeout, Instant};
+
+/// How long to wait for anything that should succeed
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn mac(id: u8) -> MacAddr6 {
+    MacAddr6::new(0xd0, 0x73, 0xd5, 0x00, 0x00, id)
+}
+
+fn device(id: u8) -> VirtualDevice {
+    VirtualDevice::new(mac(id), Label::new(format!("Bulb {}", id)))
+}
+
+fn address(simulator: SocketAddr, id: u8) -> DeviceAddress {
+    DeviceAddress::new(simulator, DeviceTarget::Targeted(mac(id)))
+}
+
+/// Start `simulator` in the background, returning its address
+fn start(simulator: Simulator) -> SocketAddr {
+    let addr = simulator.local_addr().unwrap();
+    tokio::spawn(simulator.run());
+    addr
+}
+
+/// Start a simulator hosting devices with the given ids
+async fn simulate(ids: &[u8]) -> (SocketAddr, lifx_sim::Devices) {
+    let simulator = Simulator::bind("127.0.0.1:0").await.unwrap();
+    for id in ids {
+        simulator.add_device(device(*id));
+    }
+    let devices = simulator.devices();
+    (start(simulator), devices)
+}
+
+async fn connect(config: Config) -> Client {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (client, conn) = Client::with_socket_and_config(socket, 1234, config).unwrap();
+    tokio::spawn(conn);
+    client
+}
+
+#[tokio::test]
+async fn test_discovery() {
+    let (simulator, _) = simulate(&[1, 2, 3]).await;
+    let mut client = connect(Config::default()).await;
+
+    let mut discovery = client.subscribe_discovery();
+    client.send_async(DeviceAddress::new(simulator, DeviceTarget::All), Message::GetService).await.unwrap();
+
+    let mut found = HashSet::new();
+    while found.len() < 3 {
+        let address = timeout(TIMEOUT, discovery.recv()).await.unwrap().unwrap();
+        found.insert(address);
+    }
+    let expected: HashSet<_> = [1, 2, 3].iter().map(|id| address(simulator, *id)).collect();
+    assert_eq!(found, expected);
+}
+
+#[tokio::test]
+async fn test_get_label() {
+    let (simulator, _) = simulate(&[1, 2]).await;
+    let mut client = connect(Config::default()).await;
+
+    let label = timeout(TIMEOUT, client.get_label(address(simulator, 2))).await.unwrap().unwrap();
+    assert_eq!(label, "Bulb 2");
+}
+
+#[tokio::test]
+async fn test_malformed_packets() {
+    let (simulator, _) = simulate(&[1]).await;
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client_addr = socket.local_addr().unwrap();
+    let (mut client, conn) = Client::with_socket_and_config(socket, 1234, Config::default()).unwrap();
+    tokio::spawn(conn);
+
+    // Anyone on the network can send garbage: a truncated header, a header whose payload is missing, and an oversized size field
+    let mut header_only = Vec::new();
+    lifx_proto::Packet::builder(lifx_proto::message::GetLabel).build().encode(&mut header_only).unwrap();
+    header_only[32..34].copy_from_slice(&u16::from(lifx_proto::MessageType::State).to_le_bytes());
+    let attacker = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    for datagram in [&header_only[..10], &header_only[..], &[0xff, 0xff, 0, 0]].iter() {
+        attacker.send_to(datagram, client_addr).await.unwrap();
+    }
+
+    let label = timeout(TIMEOUT, client.get_label(address(simulator, 1))).await.unwrap().unwrap();
+    assert_eq!(label, "Bulb 1");
+}
+
+#[tokio::test]
+async fn test_get_light_state() {
+    let simulator = Simulator::bind("127.0.0.1:0").await.unwrap();
+    let color = Hsbk { hue: 100, saturation: 200, brightness: 300, temperature: Kelvin::new(4000) };
+    simulator.add_device(device(1).with_color(color).with_power(0));
+    let simulator = start(simulator);
+    let mut client = connect(Config::default()).await;
+
+    let state = timeout(TIMEOUT, client.get_light_state(address(simulator, 1))).await.unwrap().unwrap();
+    assert_eq!(state.color, color);
+    assert_eq!(state.power, 0);
+    assert_eq!(state.label.as_str(), "Bulb 1");
+}
+
+#[tokio::test]
+async fn test_get_features() {
+    let simulator = Simulator::bind("127.0.0.1:0").await.unwrap();
+    simulator.add_device(device(1).with_product(1, 27).with_firmware(FirmwareVersion::new(2, 70)));
+    simulator.add_device(device(2).with_product(1, 27).with_firmware(FirmwareVersion::new(2, 80)));
+    simulator.add_device(device(3).with_product(1, 9999));
+    let simulator = start(simulator);
+    let mut client = connect(Config::default()).await;
+
+    // The same product supports warmer colors on newer firmware
+    let warm = Hsbk { hue: 0, saturation: 0, brightness: 65535, temperature: Kelvin::new(1500) };
+    let old = timeout(TIMEOUT, client.get_features(address(simulator, 1))).await.unwrap().unwrap().unwrap();
+    assert_eq!(old.check_color(warm, KelvinPolicy::Clamp).unwrap().temperature, Kelvin::new(2500));
+    let new = timeout(TIMEOUT, client.get_features(address(simulator, 2))).await.unwrap().unwrap().unwrap();
+    assert_eq!(new.check_color(warm, KelvinPolicy::Reject).unwrap(), warm);
+
+    let unknown = timeout(TIMEOUT, client.get_features(address(simulator, 3))).await.unwrap().unwrap();
+    assert_eq!(unknown, None);
+}
+
+#[tokio::test]
+async fn test_set_light_color() {
+    let (simulator, devices) = simulate(&[1]).await;
+    let mut client = connect(Config::default()).await;
+
+    let color = Hsbk { hue: 1000, saturation: 65535, brightness: 32768, temperature: Kelvin::new(2700) };
+    let set = client.set_light_color(address(simulator, 1), color, Duration::from_secs(1));
+    timeout(TIMEOUT, set).await.unwrap().unwrap();
+
+    // The device was acknowledged, so it must have already applied the change
+    assert_eq!(devices.get(mac(1)).unwrap().color(), color);
+
+    // Transitions too long for the protocol are rejected instead of wrapping around
+    let forever = Hsbk { hue: 0, ..color };
+    let set = client.set_light_color(address(simulator, 1), forever, Duration::from_secs(60 * 60 * 24 * 50));
+    assert!(matches!(timeout(TIMEOUT, set).await.unwrap(), Err(Error::Protocol(ProtocolError::DurationOutOfRange(_)))));
+    assert_eq!(devices.get(mac(1)).unwrap().color(), color);
+}
+
+#[tokio::test]
+async fn test_acknowledgement() {
+    let (simulator, devices) = simulate(&[1, 2]).await;
+    let mut client = connect(Config::default()).await;
+
+    let message = Message::SetLabel(SetLabel { label: Label::new("Renamed") });
+    let set = client.send_with_acknowledgement(address(simulator, 2), message);
+    timeout(TIMEOUT, set).await.unwrap().unwrap();
+
+    assert_eq!(devices.get(mac(2)).unwrap().label().as_str(), "Renamed");
+    assert_eq!(devices.get(mac(1)).unwrap().label().as_str(), "Bulb 1");
+}
+
+#[tokio::test]
+async fn test_acknowledgement_and_reply() {
+    let (simulator, _) = simulate(&[1]).await;
+    let mut client = connect(Config::default()).await;
+
+    let message = Message::SetLabel(SetLabel { label: Label::new("Both") });
+    let (acknowledged, reply) = client.send_with_acknowledgement_and_response(address(simulator, 1), message).await.unwrap();
+
+    // The acknowledgement arrives first, but the two can be awaited in any order
+    let reply = timeout(TIMEOUT, reply).await.unwrap().unwrap();
+    assert_eq!(reply.message(), &Message::StateLabel(StateLabel { label: Label::new("Both") }));
+    timeout(TIMEOUT, acknowledged).await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_query() {
+    let (simulator, devices) = simulate(&[1]).await;
+    let mut client = connect(Config::default()).await;
+
+    // Set messages reply with the updated state when a response is required
+    let query = client.query(address(simulator, 1), SetLabel { label: Label::new("Queried") });
+    let reply = timeout(TIMEOUT, query).await.unwrap().unwrap();
+    assert_eq!(reply.label.as_str(), "Queried");
+    assert_eq!(devices.get(mac(1)).unwrap().label().as_str(), "Queried");
+}
+
+#[tokio::test]
+async fn test_timeout() {
+    let unresponsive = Simulator::bind("127.0.0.1:0").await.unwrap().with_faults(Faults::seeded(1).with_loss(1.0));
+    unresponsive.add_device(device(1));
+    let unresponsive = start(unresponsive);
+    let (responsive, _) = simulate(&[2]).await;
+    let mut client = connect(Config::default()).await;
+
+    // Replies that never arrive don't resolve the request...
+    let result = timeout(Duration::from_millis(200), client.get_label(address(unresponsive, 1))).await;
+    assert!(result.is_err());
+
+    // ...and don't prevent other requests from completing
+    let label = timeout(TIMEOUT, client.get_label(address(responsive, 2))).await.unwrap().unwrap();
+    assert_eq!(label, "Bulb 2");
+}
+
+#[tokio::test]
+async fn test_many_timeouts() {
+    let unresponsive = Simulator::bind("127.0.0.1:0").await.unwrap().with_faults(Faults::seeded(1).with_loss(1.0));
+    unresponsive.add_device(device(1));
+    let unresponsive = start(unresponsive);
+    let (responsive, _) = simulate(&[2]).await;
+    let client = connect(Config { rate_limit: None, ..Config::default() }).await;
+
+    // More requests than there are sequence numbers time out...
+    let requests = (0..300).map(|_| {
+        let mut client = client.clone();
+        async move { timeout(Duration::from_millis(200), client.get_label(address(unresponsive, 1))).await }
+    });
+    assert!(join_all(requests).await.iter().all(Result::is_err));
+
+    // ...but give up their sequence numbers, so later requests still go through
+    let mut client = client;
+    for _ in 0..3 {
+        let label = timeout(TIMEOUT, client.get_label(address(responsive, 2))).await.unwrap().unwrap();
+        assert_eq!(label, "Bulb 2");
+    }
+}
+
+#[tokio::test]
+async fn test_concurrent_requests() {
+    let ids = [1, 2, 3, 4];
+    let (simulator, _) = simulate(&ids).await;
+    let client = connect(Config::default()).await;
+
+    let requests = ids.iter().flat_map(|id| {
+        let client = client.clone();
+        (0..5).map(move |_| {
+            let mut client = client.clone();
+            async move { (*id, client.get_label(address(simulator, *id)).await.unwrap()) }
+        })
+    });
+    let results = timeout(TIMEOUT, join_all(requests)).await.unwrap();
+
+    assert_eq!(results.len(), 20);
+    for (id, label) in results {
+        assert_eq!(label, format!("Bulb {}", id));
+    }
+}
+
+#[tokio::test]
+async fn test_animation() {
+    let (simulator, devices) = simulate(&[1, 2]).await;
+    let mut client = connect(Config::default()).await;
+
+    let red = Hsbk { hue: 0, saturation: 65535, brightness: 65535, temperature: Kelvin::new(3500) };
+    let blue = Hsbk { hue: 43690, ..red };
+    let end = Duration::from_millis(200);
+    let animation = Animation::new()
+        .with_track(Track::new(address(simulator, 1), vec![Keyframe::new(Duration::ZERO, red), Keyframe::new(end, blue)]))
+        .with_track(Track::new(address(simulator, 2), vec![Keyframe::new(Duration::ZERO, blue), Keyframe::new(end, red)]));
+    timeout(TIMEOUT, animation.run(&mut client)).await.unwrap().unwrap();
+
+    // Requests to each device are sent in order, so once a query is answered every frame before it has been applied
+    for (id, expected) in [(1, blue), (2, red)].iter().copied() {
+        let state = timeout(TIMEOUT, client.get_light_state(address(simulator, id))).await.unwrap().unwrap();
+        assert_eq!(state.color, expected);
+        assert_eq!(devices.get(mac(id)).unwrap().color(), expected);
+    }
+}
+
+#[tokio::test]
+async fn test_unreliable_network() {
+    let faults = Faults::seeded(42)
+        .with_latency(Latency::Uniform { min: Duration::from_millis(1), max: Duration::from_millis(20) })
+        .with_duplication(0.5);
+    let simulator = Simulator::bind("127.0.0.1:0").await.unwrap().with_faults(faults);
+    simulator.add_device(device(1));
+    simulator.add_device(device(2));
+    let simulator = start(simulator);
+    let client = connect(Config { rate_limit: None, ..Config::default() }).await;
+
+    // Replies arrive late, out of order and duplicated, but each still goes to the request it answers
+    let requests = (0..20).map(|i| {
+        let mut client = client.clone();
+        let id = 1 + (i % 2) as u8;
+        async move { (id, client.get_label(address(simulator, id)).await.unwrap()) }
+    });
+    for (id, label) in timeout(TIMEOUT, join_all(requests)).await.unwrap() {
+        assert_eq!(label, format!("Bulb {}", id));
+    }
+}
+
+#[tokio::test]
+async fn test_reordering() {
+    // Every other reply is held back until the next one is sent
+    let simulator = Simulator::bind("127.0.0.1:0").await.unwrap().with_faults(Faults::seeded(7).with_reordering(1.0));
+    simulator.add_device(device(1));
+    simulator.add_device(device(2));
+    let simulator = start(simulator);
+    let client = connect(Config { rate_limit: None, ..Config::default() }).await;
+
+    // A held-back reply with nothing to follow it is still sent eventually
+    let label = timeout(TIMEOUT, client.clone().get_label(address(simulator, 1))).await.unwrap().unwrap();
+    assert_eq!(label, "Bulb 1");
+
+    // Replies that arrive swapped still go to the requests they answer
+    let requests = (0..20).map(|i| {
+        let mut client = client.clone();
+        let id = 1 + (i % 2) as u8;
+        async move { (id, client.get_label(address(simulator, id)).await.unwrap()) }
+    });
+    for (id, label) in timeout(TIMEOUT, join_all(requests)).await.unwrap() {
+        assert_eq!(label, format!("Bulb {}", id));
+    }
+}
+
+#[tokio::test]
+async fn test_packet_loss() {
+    let simulator = Simulator::bind("127.0.0.1:0").await.unwrap().with_faults(Faults::seeded(3).with_loss(0.3));
+    simulator.add_device(device(1));
+    let simulator = start(simulator);
+    let mut client = connect(Config { rate_limit: None, ..Config::default() }).await;
+
+    // Lost requests or replies never resolve, but the ones that get through are answered correctly
+    let mut answered = 0;
+    for _ in 0..20 {
+        if let Ok(label) = timeout(Duration::from_millis(100), client.get_label(address(simulator, 1))).await {
+            assert_eq!(label.unwrap(), "Bulb 1");
+            answered += 1;
+        }
+    }
+    assert!(answered > 0 && answered < 20, "{} of 20 requests answered", answered);
+}
+
+#[tokio::test]
+async fn test_rate_limit() {
+    let (simulator, _) = simulate(&[1, 2]).await;
+    let client = connect(Config { rate_limit: Some(RateLimit::per_second(20.0)), ..Config::default() }).await;
+
+    // Five requests to each device take about 200ms, since the devices are limited separately
+    let start = Instant::now();
+    let requests = (0..10).map(|i| {
+        let mut client = client.clone();
+        let id = 1 + (i % 2) as u8;
+        async move { client.get_label(address(simulator, id)).await.unwrap() }
+    });
+    timeout(TIMEOUT, join_all(requests)).await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(200), "took {:?}", start.elapsed());
+
+    let metrics = client.rate_limit_metrics();
+    assert_eq!(metrics.requests(), 10);
+    assert!(metrics.delayed_requests() >= 8, "{} requests delayed", metrics.delayed_requests());
+    assert!(metrics.max_wait() >= Duration::from_millis(150), "max wait {:?}", metrics.max_wait());
+}
+
+#[tokio::test]
+async fn test_coalesce() {
+    let (simulator, devices) = simulate(&[1]).await;
+    let config = Config { rate_limit: Some(RateLimit::per_second(10.0)), coalesce: true, ..Config::default() };
+    let mut client = connect(config).await;
+
+    // The first color goes out immediately, and the rest replace each other while waiting for the rate limit
+    let color = |hue| Hsbk { hue, saturation: 65535, brightness: 65535, temperature: Kelvin::new(3500) };
+    for hue in 0..10 {
+        client.send_async(address(simulator, 1), Message::SetColor(SetColor { color: color(hue * 1000), duration: ProtocolDuration::ZERO })).await.unwrap();
+    }
+    let state = timeout(TIMEOUT, client.get_light_state(address(simulator, 1))).await.unwrap().unwrap();
+    assert_eq!(state.color, color(9000));
+    assert_eq!(devices.get(mac(1)).unwrap().color(), color(9000));
+    let metrics = client.rate_limit_metrics();
+    assert!(metrics.coalesced_requests() >= 8, "{} requests coalesced", metrics.coalesced_requests());
+    assert_eq!(metrics.requests() + metrics.coalesced_requests(), 11);
+}
+
+#[tokio::test]
+async fn test_try_send() {
+    let (simulator, devices) = simulate(&[1]).await;
+    let config = Config { rate_limit: Some(RateLimit::per_second(10.0)), queue_capacity: 2, ..Config::default() };
+    let mut client = connect(config).await;
+
+    // The queues fill up faster than the rate limit drains them
+    let label = |i| Message::SetLabel(SetLabel { label: Label::new(format!("Label {}", i)) });
+    let mut sent = 0;
+    while sent < 100 {
+        match client.try_send(address(simulator, 1), label(sent)) {
+            Ok(()) => sent += 1,
+            Err(Error::QueueFull) => break,
+            Err(err) => panic!("unexpected error: {}", err),
+        }
+        tokio::task::yield_now().await;
+    }
+    assert!(sent < 100, "queue never filled up");
+
+    // Everything that was accepted is still sent, in order
+    let reply = timeout(TIMEOUT, client.get_label(address(simulator, 1))).await.unwrap().unwrap();
+    assert_eq!(reply, format!("Label {}", sent - 1));
+    assert_eq!(devices.get(mac(1)).unwrap().label().as_str(), format!("Label {}", sent - 1));
+}
+
+#[tokio::test]
+async fn test_backlog_does_not_block_other_devices() {
+    let (busy, _) = simulate(&[1]).await;
+    let (idle, _) = simulate(&[2]).await;
+    let config = Config { rate_limit: Some(RateLimit::per_second(10.0)), queue_capacity: 5, ..Config::default() };
+    let client = connect(config).await;
+
+    // Far more requests than the busy device's queue holds, which take about 3 seconds to send
+    let mut flooding = client.clone();
+    let flood = tokio::spawn(async move {
+        for i in 0..30 {
+            let message = Message::SetLabel(SetLabel { label: Label::new(format!("Label {}", i)) });
+            flooding.send_async(address(busy, 1), message).await.unwrap();
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // The other device is still answered right away
+    let start = Instant::now();
+    let label = timeout(TIMEOUT, client.clone().get_label(address(idle, 2))).await.unwrap().unwrap();
+    assert_eq!(label, "Bulb 2");
+    assert!(start.elapsed() < Duration::from_millis(500), "took {:?}", start.elapsed());
+    assert!(!flood.is_finished());
+    flood.abort();
+}
+
+#[tokio::test]
+async fn test_scan() {
+    // Scanning sends unicast discovery to the standard port, so the simulator has to listen there
+    let simulator = Simulator::bind(("127.0.0.1", DeviceAddress::DISCOVERY_PORT)).await.expect("discovery port is in use");
+    let ids: Vec<u8> = (1..=20).collect();
+    for id in &ids {
+        simulator.add_device(device(*id));
+    }
+    let simulator = start(simulator);
+    let client = connect(Config::default()).await;
+
+    // More devices than fit in a small channel answer at once, and a zero interval means no delay between hosts
+    let mut discovery = client.scan(vec![[127, 0, 0, 1].into()], Duration::ZERO);
+    let mut found = HashSet::new();
+    while found.len() < ids.len() {
+        let address = timeout(TIMEOUT, discovery.recv()).await.unwrap().unwrap();
+        found.insert(address);
+    }
+    let expected: HashSet<_> = ids.iter().map(|id| address(simulator, *id)).collect();
+    assert_eq!(found, expected);
+}
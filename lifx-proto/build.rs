@@ -0,0 +1,98 @@
+//! Generates the product table in `src/products.rs` from `products.json`, which is a copy of the LIFX products registry
+//! (<https://github.com/LIFX/products>). To update the table, replace `products.json` with a newer copy.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use serde_json::{Map, Value};
+
+const FEATURES: &[&str] = &["color", "infrared", "multizone", "extended_multizone", "chain", "matrix", "hev", "relays", "buttons"];
+
+fn main() {
+    println!("cargo:rerun-if-changed=products.json");
+
+    let registry = fs::read_to_string("products.json").expect("could not read products.json");
+    let registry: Value = serde_json::from_str(&registry).expect("invalid products.json");
+
+    let mut products = Vec::new();
+    for vendor in registry.as_array().expect("expected a list of vendors") {
+        let vid = vendor["vid"].as_u64().expect("missing vendor id");
+        let defaults = vendor["defaults"].as_object().expect("missing vendor defaults");
+
+        for product in vendor["products"].as_array().expect("missing product list") {
+            let pid = product["pid"].as_u64().expect("missing product id");
+            let name = product["name"].as_str().expect("missing product name");
+
+            let mut features = defaults.clone();
+            apply(&mut features, &product["features"]);
+
+            // Firmware upgrades are cumulative, so generate the full feature set as of each upgrade
+            let mut upgrades = product["upgrades"].as_array().cloned().unwrap_or_default();
+            upgrades.sort_by_key(|upgrade| (upgrade["major"].as_u64(), upgrade["minor"].as_u64()));
+            let mut upgraded = features.clone();
+            let upgrades: Vec<String> = upgrades
+                .iter()
+                .map(|upgrade| {
+                    apply(&mut upgraded, &upgrade["features"]);
+                    let major = upgrade["major"].as_u64().expect("missing upgrade major version");
+                    let minor = upgrade["minor"].as_u64().expect("missing upgrade minor version");
+                    format!("(FirmwareVersion {{ major: {}, minor: {} }}, {})", major, minor, features_literal(&upgraded))
+                })
+                .collect();
+
+            products.push((vid, pid, name.to_string(), features_literal(&features), upgrades));
+        }
+    }
+
+    // Sorted so lookups can use a binary search
+    products.sort_by_key(|(vid, pid, ..)| (*vid, *pid));
+
+    let mut code = String::from("static PRODUCTS: &[Product] = &[\n");
+    for (vid, pid, name, features, upgrades) in products {
+        writeln!(
+            code,
+            "    Product {{ vendor: {}, product: {}, name: {:?}, features: {}, upgrades: &[{}] }},",
+            vid,
+            pid,
+            name,
+            features,
+            upgrades.join(", ")
+        )
+        .unwrap();
+    }
+    code.push_str("];\n");
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("products.rs");
+    fs::write(out, code).expect("could not write product table");
+}
+
+/// Overwrite the features in `features` with any set in `changes`
+fn apply(features: &mut Map<String, Value>, changes: &Value) {
+    if let Some(changes) = changes.as_object() {
+        for (feature, value) in changes {
+            features.insert(feature.clone(), value.clone());
+        }
+    }
+}
+
+fn features_literal(features: &Map<String, Value>) -> String {
+    let mut literal = String::from("Features { ");
+    for feature in FEATURES {
+        let enabled = features.get(*feature).and_then(Value::as_bool).unwrap_or(false);
+        write!(literal, "{}: {}, ", feature, enabled).unwrap();
+    }
+
+    let temperature_range = match features.get("temperature_range").and_then(Value::as_array) {
+        Some(range) => {
+            let min = range[0].as_u64().expect("invalid temperature range");
+            let max = range[1].as_u64().expect("invalid temperature range");
+            assert!(min <= max, "empty temperature range {}-{}", min, max);
+            format!("Some(TemperatureRange::new(Kelvin::new({}), Kelvin::new({})))", min, max)
+        }
+        None => "None".to_string(),
+    };
+    write!(literal, "temperature_range: {} }}", temperature_range).unwrap();
+    literal
+}
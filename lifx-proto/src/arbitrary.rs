@@ -0,0 +1,183 @@
+//! [`proptest`] generators for protocol types. These are used by the crate's own property tests, and are available to other crates through the
+//! `proptest` feature. The generator for [`Message`] is defined along with the message table, so that it covers every message.
+
+
+use macaddr::MacAddr6;
+use proptest::prelude::*;
+
+use crate::color::{Hsbk, Kelvin};
+use crate::duration::ProtocolDuration;
+use crate::header::{DeviceTarget, Header};
+use crate::label::Label;
+use crate::message::{Message, MessageType, Service, SetColor, SetLabel, State, StateHostFirmware, StateLabel, StateService, StateVersion};
+use crate::products::FirmwareVersion;
+use crate::Packet;
+
+impl Arbitrary for Kelvin {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Kelvin>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<u16>().prop_map(Kelvin::new).boxed()
+    }
+}
+
+impl Arbitrary for Hsbk {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Hsbk>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<u16>(), any::<u16>(), any::<u16>(), any::<Kelvin>())
+            .prop_map(|(hue, saturation, brightness, temperature)| Hsbk { hue, saturation, brightness, temperature })
+            .boxed()
+    }
+}
+
+impl Arbitrary for Label {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Label>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        // Labels are NUL-padded on the wire, so they can't end with NUL characters themselves
+        "[^\\x00]{0,32}"
+            .prop_map(|mut label| {
+                while label.len() > Label::MAX_LENGTH {
+                    label.pop();
+                }
+                Label::new(label)
+            })
+            .boxed()
+    }
+}
+
+impl Arbitrary for DeviceTarget {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<DeviceTarget>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            Just(DeviceTarget::All),
+            any::<[u8; 6]>().prop_map(|mac| DeviceTarget::Targeted(MacAddr6::from(mac))),
+        ]
+        .boxed()
+    }
+}
+
+impl Arbitrary for MessageType {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<MessageType>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<u16>().prop_map(MessageType::from).boxed()
+    }
+}
+
+impl Arbitrary for Header {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Header>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<u16>(), any::<u32>(), any::<DeviceTarget>(), any::<bool>(), any::<bool>(), any::<u8>(), any::<MessageType>())
+            .prop_map(|(size, source, target, response_required, acknowledgement_required, sequence, message_type)| Header {
+                size,
+                source,
+                target,
+                response_required,
+                acknowledgement_required,
+                sequence,
+                message_type,
+            })
+            .boxed()
+    }
+}
+
+impl Arbitrary for Service {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Service>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<u8>().prop_map(Service::from).boxed()
+    }
+}
+
+impl Arbitrary for StateService {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<StateService>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<Service>(), any::<u32>()).prop_map(|(service, port)| StateService { service, port }).boxed()
+    }
+}
+
+impl Arbitrary for StateHostFirmware {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<StateHostFirmware>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<u64>(), any::<u16>(), any::<u16>())
+            .prop_map(|(build, major, minor)| StateHostFirmware { build, version: FirmwareVersion::new(major, minor) })
+            .boxed()
+    }
+}
+
+impl Arbitrary for StateVersion {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<StateVersion>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<u32>(), any::<u32>()).prop_map(|(vendor, product)| StateVersion { vendor, product }).boxed()
+    }
+}
+
+impl Arbitrary for SetLabel {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<SetLabel>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<Label>().prop_map(|label| SetLabel { label }).boxed()
+    }
+}
+
+impl Arbitrary for StateLabel {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<StateLabel>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<Label>().prop_map(|label| StateLabel { label }).boxed()
+    }
+}
+
+impl Arbitrary for SetColor {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<SetColor>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<Hsbk>(), any::<u32>())
+            .prop_map(|(color, millis)| SetColor { color, duration: ProtocolDuration::from_millis(millis) })
+            .boxed()
+    }
+}
+
+impl Arbitrary for State {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<State>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<Hsbk>(), any::<u16>(), any::<Label>())
+            .prop_map(|(color, power, label)| State { color, power, label })
+            .boxed()
+    }
+}
+
+impl Arbitrary for Packet {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Packet>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<u32>(), any::<DeviceTarget>(), any::<u8>(), any::<bool>(), any::<bool>(), any::<Message>())
+            .prop_map(|(source, target, sequence, response_required, acknowledgement_required, message)| {
+                Packet::new(source, target, sequence, response_required, acknowledgement_required, message)
+            })
+            .boxed()
+    }
+}
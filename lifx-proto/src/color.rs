@@ -1,12 +1,19 @@
-use std::{convert::TryFrom, f32, u16};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
+use core::str::FromStr;
 
 use bytes::{Buf, BufMut};
-use thiserror::Error;
 
+use crate::math::{cbrt, powf, round, scale_unit, unit};
+use crate::products::TemperatureRange;
+use crate::rgb::Rgb;
 use crate::ProtocolError;
 
 /// Color and color temperature, represented in HSB and Kelvin
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Hsbk {
     pub hue: u16,
     pub saturation: u16,
@@ -14,7 +21,12 @@ pub struct Hsbk {
     pub temperature: Kelvin,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Color temperature, in Kelvin.
+///
+/// Any temperature can be represented, since devices may report values outside the range a client expects. Use
+/// [`TemperatureRange`](crate::products::TemperatureRange) to check a temperature against what a product supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(from = "u16", into = "u16"))]
 pub struct Kelvin(u16);
 
 impl Hsbk {
@@ -32,7 +44,7 @@ impl Hsbk {
         let hue = buf.get_u16_le();
         let saturation = buf.get_u16_le();
         let brightness = buf.get_u16_le();
-        let temperature = Kelvin::try_from(buf.get_u16_le()).map_err(|err| ProtocolError::InvalidPayload(err.to_string()))?;
+        let temperature = Kelvin(buf.get_u16_le());
         Ok(Hsbk { 
             hue,
             saturation,
@@ -43,20 +55,276 @@ impl Hsbk {
 }
 
 impl Kelvin {
-    pub fn new(value: u16) -> Kelvin {
-        Kelvin::try_from(value).expect("Temperature out of bounds")
+    pub const fn new(value: u16) -> Kelvin {
+        Kelvin(value)
+    }
+}
+
+impl From<u16> for Kelvin {
+    fn from(value: u16) -> Kelvin {
+        Kelvin(value)
+    }
+}
+
+impl fmt::Display for Kelvin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}K", self.0)
+    }
+}
+
+/// A mapping between device brightness and perceived lightness, both from 0 to 65535.
+///
+/// LIFX brightness is proportional to light output, but eyes are more sensitive to changes in dim light, so a linear fade appears to jump
+/// at the low end. Fading or stepping evenly in lightness instead looks smooth. Convert a lightness to a brightness to send to a device
+/// with [`BrightnessCurve::to_brightness`], and back with [`BrightnessCurve::to_lightness`].
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BrightnessCurve {
+    /// Lightness is the same as brightness
+    #[default]
+    Linear,
+    /// [CIE 1976 lightness](https://en.wikipedia.org/wiki/CIELAB_color_space#Converting_between_CIELAB_and_CIEXYZ_coordinates) (L*), the
+    /// standard model of perceived lightness
+    CieLightness,
+    /// Brightness is lightness raised to a power. A gamma of 2.2 is close to the sRGB curve.
+    Gamma(Gamma),
+    /// A custom curve, such as one measured for a particular device
+    Lut(Lut),
+}
+
+impl BrightnessCurve {
+    /// The device brightness with perceived lightness `lightness`
+    pub fn to_brightness(&self, lightness: u16) -> u16 {
+        match self {
+            BrightnessCurve::Linear => lightness,
+            BrightnessCurve::CieLightness => {
+                // The curve is linear near black, and cubic above that
+                let lightness = unit(lightness) * 100.0;
+                let luminance = if lightness <= CIE_KAPPA * CIE_EPSILON {
+                    lightness / CIE_KAPPA
+                } else {
+                    let cube_root = (lightness + 16.0) / 116.0;
+                    cube_root * cube_root * cube_root
+                };
+                scale_unit(luminance)
+            }
+            BrightnessCurve::Gamma(gamma) => scale_unit(powf(unit(lightness), gamma.0)),
+            BrightnessCurve::Lut(lut) => lut.to_brightness(lightness),
+        }
+    }
+
+    /// The perceived lightness of device brightness `brightness`. This is the inverse of [`BrightnessCurve::to_brightness`], up to rounding.
+    pub fn to_lightness(&self, brightness: u16) -> u16 {
+        match self {
+            BrightnessCurve::Linear => brightness,
+            BrightnessCurve::CieLightness => {
+                let luminance = unit(brightness);
+                let lightness = if luminance <= CIE_EPSILON {
+                    luminance * CIE_KAPPA
+                } else {
+                    116.0 * cbrt(luminance) - 16.0
+                };
+                scale_unit(lightness / 100.0)
+            }
+            BrightnessCurve::Gamma(gamma) => scale_unit(powf(unit(brightness), gamma.0.recip())),
+            BrightnessCurve::Lut(lut) => lut.to_lightness(brightness),
+        }
     }
 }
 
-impl TryFrom<u16> for Kelvin {
-    type Error = KelvinError;
+/// Luminance where the CIE lightness curve switches from linear to cubic, `(6/29)^3`
+const CIE_EPSILON: f32 = 216.0 / 24389.0;
+/// Slope of the linear part of the CIE lightness curve, `(29/3)^3`
+const CIE_KAPPA: f32 = 24389.0 / 27.0;
+
+/// The exponent of a [`BrightnessCurve::Gamma`] curve, which must be a positive, finite number.
+///
+/// ```
+/// use lifx_proto::color::{BrightnessCurve, Gamma};
+///
+/// let curve = BrightnessCurve::Gamma(Gamma::new(2.0).unwrap());
+/// assert_eq!(curve.to_brightness(32768), 16384);
+/// assert!(Gamma::new(0.0).is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(try_from = "f32", into = "f32"))]
+pub struct Gamma(f32);
 
-    fn try_from(value: u16) -> Result<Self, Self::Error> {
-        match value {
-            2500..=9000 => Ok(Kelvin(value)),
-            _ => Err(KelvinError(value))
+impl Gamma {
+    pub fn new(gamma: f32) -> Result<Gamma, GammaError> {
+        // Also rejects NaN
+        if gamma > 0.0 && gamma < f32::INFINITY {
+            Ok(Gamma(gamma))
+        } else {
+            Err(GammaError(gamma))
         }
     }
+
+    pub fn value(self) -> f32 {
+        self.0
+    }
+}
+
+impl TryFrom<f32> for Gamma {
+    type Error = GammaError;
+
+    fn try_from(gamma: f32) -> Result<Gamma, GammaError> {
+        Gamma::new(gamma)
+    }
+}
+
+impl From<Gamma> for f32 {
+    fn from(gamma: Gamma) -> f32 {
+        gamma.0
+    }
+}
+
+/// A gamma that isn't positive and finite. This contains the invalid value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GammaError(f32);
+
+impl fmt::Display for GammaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "gamma must be positive and finite, not {}", self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for GammaError {}
+
+/// A brightness curve defined by a table of brightnesses at evenly spaced lightnesses, from lightness 0 to 65535. Lightnesses between
+/// entries are linearly interpolated.
+///
+/// ```
+/// use lifx_proto::color::{BrightnessCurve, Lut};
+///
+/// // Brightness is roughly the square of lightness
+/// let curve = BrightnessCurve::Lut(Lut::new(vec![0, 4096, 16384, 36864, 65535]).unwrap());
+/// assert_eq!(curve.to_brightness(16384), 4096);
+/// assert_eq!(curve.to_lightness(4096), 16384);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(try_from = "Vec<u16>", into = "Vec<u16>"))]
+pub struct Lut {
+    table: Vec<u16>,
+}
+
+impl Lut {
+    /// Create a table from brightnesses at evenly spaced lightnesses. There must be at least two entries, and brightness can't decrease as
+    /// lightness increases, or the curve couldn't be inverted.
+    pub fn new(table: Vec<u16>) -> Result<Lut, LutError> {
+        if table.len() < 2 || table.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(LutError(()));
+        }
+        Ok(Lut { table })
+    }
+
+    pub fn as_slice(&self) -> &[u16] {
+        &self.table
+    }
+
+    fn to_brightness(&self, lightness: u16) -> u16 {
+        let segments = self.table.len() - 1;
+        let position = unit(lightness) * segments as f32;
+        // Truncation is the same as flooring, since the position is never negative. Full lightness is the end of the last segment.
+        let idx = (position as usize).min(segments - 1);
+        lerp_u16(self.table[idx], self.table[idx + 1], position - idx as f32)
+    }
+
+    fn to_lightness(&self, brightness: u16) -> u16 {
+        let segments = self.table.len() - 1;
+        // The first entry at or above `brightness`. Where the table is flat, this picks the lowest lightness.
+        let end = self.table.partition_point(|entry| *entry < brightness);
+        if end == 0 {
+            return 0;
+        } else if end > segments {
+            return u16::MAX;
+        }
+
+        let (low, high) = (self.table[end - 1], self.table[end]);
+        let fraction = f32::from(brightness - low) / f32::from(high - low);
+        scale_unit((end - 1) as f32 / segments as f32 + fraction / segments as f32)
+    }
+}
+
+impl TryFrom<Vec<u16>> for Lut {
+    type Error = LutError;
+
+    fn try_from(table: Vec<u16>) -> Result<Lut, LutError> {
+        Lut::new(table)
+    }
+}
+
+impl From<Lut> for Vec<u16> {
+    fn from(lut: Lut) -> Vec<u16> {
+        lut.table
+    }
+}
+
+/// A brightness table that's too short or decreasing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LutError(());
+
+impl fmt::Display for LutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("brightness table must have at least 2 entries and never decrease")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for LutError {}
+
+impl Hsbk {
+    /// The color `t` of the way from `self` to `to`, where `t` is from 0 to 1. Values of `t` outside that range are clamped.
+    ///
+    /// Brightness changes evenly along `curve`, so use [`BrightnessCurve::CieLightness`] for fades that look even, or
+    /// [`BrightnessCurve::Linear`] to change light output at a constant rate. Hue takes the shorter way around the color wheel, so fading
+    /// from red to magenta doesn't pass through green and blue. Color temperature is blended in [mireds](https://en.wikipedia.org/wiki/Mired),
+    /// which changes at a more even perceived rate than Kelvin.
+    pub fn interpolate(&self, to: Hsbk, t: f32, curve: &BrightnessCurve) -> Hsbk {
+        // Written so that NaN is treated as 0
+        let t = if t > 0.0 { t.min(1.0) } else { 0.0 };
+
+        // Hues are angles, so the difference wraps around. Reinterpreting it as an i16 picks the shorter direction.
+        let hue_delta = to.hue.wrapping_sub(self.hue) as i16;
+        let hue = self.hue.wrapping_add(round(f32::from(hue_delta) * t) as i16 as u16);
+
+        // Hit the endpoints exactly, even if the curve doesn't round-trip
+        let brightness = if t == 0.0 {
+            self.brightness
+        } else if t == 1.0 {
+            to.brightness
+        } else {
+            curve.to_brightness(lerp_u16(curve.to_lightness(self.brightness), curve.to_lightness(to.brightness), t))
+        };
+
+        Hsbk {
+            hue,
+            saturation: lerp_u16(self.saturation, to.saturation, t),
+            brightness,
+            temperature: self.temperature.interpolate(to.temperature, t),
+        }
+    }
+}
+
+impl Kelvin {
+    /// Blend temperatures in mireds (reciprocal megakelvin). A temperature of 0 can't be converted, so it's blended linearly instead.
+    fn interpolate(self, to: Kelvin, t: f32) -> Kelvin {
+        if self.0 == 0 || to.0 == 0 {
+            return Kelvin(lerp_u16(self.0, to.0, t));
+        }
+
+        let mired = |kelvin: Kelvin| 1_000_000.0 / f32::from(kelvin.0);
+        let (from, to) = (mired(self), mired(to));
+        // The result is between the two temperatures, so it always fits
+        Kelvin(round(1_000_000.0 / (from + (to - from) * t)) as u16)
+    }
+}
+
+/// Linearly interpolate between two components
+fn lerp_u16(from: u16, to: u16, t: f32) -> u16 {
+    let delta = f32::from(to) - f32::from(from);
+    round(f32::from(from) + delta * t) as u16
 }
 
 #[cfg(feature = "palette")]
@@ -82,19 +350,337 @@ impl Hsbk {
     pub fn color(&self) ->  palette::Hsv {
         use palette::{Component, Hsv, RgbHue};
         // Scale the 0-2^16 value to an 0-360 value
-        let hue = RgbHue::from_degrees((self.hue as f32) / (std::u16::MAX as f32) * 360f32);
+        let hue = RgbHue::from_degrees((self.hue as f32) / (u16::MAX as f32) * 360f32);
         let saturation = self.saturation.convert::<f32>();
         let value = self.brightness.convert::<f32>();
         Hsv::new(hue, saturation, value)
     }
 }
 
-#[derive(Error, Debug)]
-#[error("invalid Kelvin value: {0}")]
-pub struct KelvinError(u16);
+/// A color temperature that a product doesn't support
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KelvinError {
+    pub(crate) temperature: Kelvin,
+    pub(crate) range: Option<TemperatureRange>,
+}
 
-impl Into<u16> for Kelvin {
-    fn into(self) -> u16 {
-        self.0
+impl KelvinError {
+    /// The unsupported temperature
+    pub fn temperature(&self) -> Kelvin {
+        self.temperature
+    }
+
+    /// The range of temperatures the product does support, or `None` if it doesn't emit light
+    pub fn range(&self) -> Option<TemperatureRange> {
+        self.range
+    }
+}
+
+impl fmt::Display for KelvinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.range {
+            Some(range) => write!(f, "{} is outside the supported range of {}", self.temperature, range),
+            None => write!(f, "{} is not supported by a product that doesn't emit light", self.temperature),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for KelvinError {}
+
+impl From<Kelvin> for u16 {
+    fn from(kelvin: Kelvin) -> u16 {
+        kelvin.0
+    }
+}
+
+/// Some components of a [`Hsbk`] color, to apply on top of a device's current color.
+///
+/// This can be parsed from the color strings used by the [LIFX HTTP API](https://api.developer.lifx.com/docs/colors), which are made up of
+/// space-separated components:
+///
+/// * Named colors: `white`, `red`, `orange`, `yellow`, `cyan`, `green`, `blue`, `purple`, and `pink`. `white` only sets saturation to 0, and the
+///   others set hue and full saturation.
+/// * Hex colors, like `#ff8800`, and RGB colors, like `rgb:255,136,0`, which set hue, saturation, and brightness
+/// * `hue:[0-360]`, in degrees
+/// * `saturation:[0.0-1.0]` and `brightness:[0.0-1.0]`
+/// * `kelvin:[temperature]`, which also sets saturation to 0
+///
+/// Later components override earlier ones.
+///
+/// ```
+/// use lifx_proto::color::{Hsbk, Kelvin, PartialHsbk};
+///
+/// let current = Hsbk { hue: 0, saturation: 0, brightness: 65535, temperature: Kelvin::new(3500) };
+/// let change: PartialHsbk = "red saturation:0.5".parse().unwrap();
+/// let color = change.apply(current);
+/// assert_eq!(color, Hsbk { hue: 0, saturation: 32768, brightness: 65535, temperature: Kelvin::new(3500) });
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PartialHsbk {
+    pub hue: Option<u16>,
+    pub saturation: Option<u16>,
+    pub brightness: Option<u16>,
+    pub temperature: Option<Kelvin>,
+}
+
+impl PartialHsbk {
+    /// Fill in any components that aren't set from `base`
+    pub fn apply(&self, base: Hsbk) -> Hsbk {
+        Hsbk {
+            hue: self.hue.unwrap_or(base.hue),
+            saturation: self.saturation.unwrap_or(base.saturation),
+            brightness: self.brightness.unwrap_or(base.brightness),
+            temperature: self.temperature.unwrap_or(base.temperature),
+        }
+    }
+
+    /// Combine this with `other`, preferring the components set in `other`
+    pub fn merge(&self, other: PartialHsbk) -> PartialHsbk {
+        PartialHsbk {
+            hue: other.hue.or(self.hue),
+            saturation: other.saturation.or(self.saturation),
+            brightness: other.brightness.or(self.brightness),
+            temperature: other.temperature.or(self.temperature),
+        }
+    }
+
+    fn parse_component(component: &str) -> Option<PartialHsbk> {
+        let hue = |degrees: u16| PartialHsbk { hue: Some(scale_hue(f32::from(degrees))), saturation: Some(u16::MAX), ..PartialHsbk::default() };
+        let lower = component.to_ascii_lowercase();
+        let partial = match lower.as_str() {
+            "white" => PartialHsbk { saturation: Some(0), ..PartialHsbk::default() },
+            "red" => hue(0),
+            "orange" => hue(36),
+            "yellow" => hue(60),
+            "green" => hue(120),
+            "cyan" => hue(180),
+            "blue" => hue(250),
+            "purple" => hue(280),
+            "pink" => hue(325),
+            _ if lower.starts_with('#') => PartialHsbk::from(Rgb::from_hex(&lower).ok()?),
+            _ => {
+                let (name, value) = lower.split_at(lower.find(':')?);
+                let value = &value[1..];
+                match name {
+                    "hue" => PartialHsbk { hue: Some(scale_hue(parse_in_range(value, 360.0)?)), ..PartialHsbk::default() },
+                    "saturation" => PartialHsbk { saturation: Some(scale_unit(parse_in_range(value, 1.0)?)), ..PartialHsbk::default() },
+                    "brightness" => PartialHsbk { brightness: Some(scale_unit(parse_in_range(value, 1.0)?)), ..PartialHsbk::default() },
+                    "kelvin" => PartialHsbk { saturation: Some(0), temperature: Some(Kelvin(value.parse().ok()?)), ..PartialHsbk::default() },
+                    "rgb" => {
+                        let mut components = value.split(',').map(|c| c.parse::<u8>().ok());
+                        let rgb = Rgb::new(components.next()??, components.next()??, components.next()??);
+                        if components.next().is_some() {
+                            return None;
+                        }
+                        PartialHsbk::from(rgb)
+                    }
+                    _ => return None,
+                }
+            }
+        };
+        Some(partial)
+    }
+}
+
+/// Sets hue, saturation, and brightness
+impl From<Rgb> for PartialHsbk {
+    fn from(rgb: Rgb) -> PartialHsbk {
+        // The temperature is ignored
+        let color = Hsbk::from_rgb(rgb, Kelvin(0));
+        PartialHsbk { hue: Some(color.hue), saturation: Some(color.saturation), brightness: Some(color.brightness), temperature: None }
+    }
+}
+
+impl From<Hsbk> for PartialHsbk {
+    fn from(color: Hsbk) -> PartialHsbk {
+        PartialHsbk {
+            hue: Some(color.hue),
+            saturation: Some(color.saturation),
+            brightness: Some(color.brightness),
+            temperature: Some(color.temperature),
+        }
+    }
+}
+
+impl FromStr for PartialHsbk {
+    type Err = ColorParseError;
+
+    fn from_str(s: &str) -> Result<PartialHsbk, ColorParseError> {
+        let mut components = s.split_whitespace().peekable();
+        if components.peek().is_none() {
+            return Err(ColorParseError(s.to_string()));
+        }
+
+        components.try_fold(PartialHsbk::default(), |partial, component| match PartialHsbk::parse_component(component) {
+            Some(parsed) => Ok(partial.merge(parsed)),
+            None => Err(ColorParseError(component.to_string())),
+        })
+    }
+}
+
+/// A color string that couldn't be parsed. This contains the invalid component.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColorParseError(String);
+
+impl fmt::Display for ColorParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid color: {:?}", self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ColorParseError {}
+
+/// Parse a number from 0 to `max`
+fn parse_in_range(value: &str, max: f32) -> Option<f32> {
+    value.parse().ok().filter(|value| (0.0..=max).contains(value))
+}
+
+/// Scale a hue in degrees to a LIFX hue
+fn scale_hue(degrees: f32) -> u16 {
+    scale_unit(degrees / 360.0)
+}
+
+
+#[test]
+fn test_parse_color() {
+    let parse = |s: &str| s.parse::<PartialHsbk>();
+    let partial = |hue, saturation, brightness, temperature: Option<u16>| PartialHsbk {
+        hue,
+        saturation,
+        brightness,
+        temperature: temperature.map(Kelvin),
+    };
+
+    assert_eq!(parse("red"), Ok(partial(Some(0), Some(65535), None, None)));
+    assert_eq!(parse("Blue"), Ok(partial(Some(45510), Some(65535), None, None)));
+    assert_eq!(parse("white"), Ok(partial(None, Some(0), None, None)));
+    assert_eq!(parse("kelvin:2700"), Ok(partial(None, Some(0), None, Some(2700))));
+    assert_eq!(parse("hue:120"), Ok(partial(Some(21845), None, None, None)));
+    assert_eq!(parse("#ff8800"), Ok(partial(Some(5825), Some(65535), Some(65535), None)));
+    assert_eq!(parse("rgb:255,136,0"), parse("#ff8800"));
+    assert_eq!(parse("red saturation:0.5 brightness:0.3"), Ok(partial(Some(0), Some(32768), Some(19661), None)));
+    assert_eq!(parse("  kelvin:3500   brightness:1 "), Ok(partial(None, Some(0), Some(65535), Some(3500))));
+
+    // Later components win
+    assert_eq!(parse("kelvin:2700 saturation:1"), Ok(partial(None, Some(65535), None, Some(2700))));
+    assert_eq!(parse("saturation:1 kelvin:2700"), Ok(partial(None, Some(0), None, Some(2700))));
+
+    for invalid in ["", " ", "rojo", "hue:361", "hue:-1", "saturation:1.5", "brightness:NaN", "kelvin:warm", "kelvin:70000", "#ff88", "rgb:1,2",
+        "rgb:1,2,3,4", "rgb:256,0,0", "hue", "hue:"].iter()
+    {
+        assert!(parse(invalid).is_err(), "{:?} should not parse", invalid);
     }
-}
\ No newline at end of file
+    assert_eq!(parse("red sat:1").unwrap_err().to_string(), "invalid color: \"sat:1\"");
+}
+
+#[test]
+fn test_apply_color() {
+    let current = Hsbk { hue: 100, saturation: 200, brightness: 300, temperature: Kelvin(4000) };
+    let warm: PartialHsbk = "kelvin:2700".parse().unwrap();
+    assert_eq!(warm.apply(current), Hsbk { hue: 100, saturation: 0, brightness: 300, temperature: Kelvin(2700) });
+    assert_eq!(PartialHsbk::default().apply(current), current);
+    assert_eq!(PartialHsbk::from(current).apply(Hsbk { hue: 0, saturation: 0, brightness: 0, temperature: Kelvin(9000) }), current);
+}
+
+#[test]
+fn test_interpolate() {
+    let linear = &BrightnessCurve::Linear;
+    let cie = &BrightnessCurve::CieLightness;
+
+    let from = Hsbk { hue: 0, saturation: 0, brightness: 0, temperature: Kelvin(2500) };
+    let to = Hsbk { hue: 20000, saturation: 65535, brightness: 65535, temperature: Kelvin(9000) };
+    assert_eq!(from.interpolate(to, 0.0, linear), from);
+    assert_eq!(from.interpolate(to, 1.0, linear), to);
+    assert_eq!(from.interpolate(to, 2.0, cie), to);
+    assert_eq!(from.interpolate(to, -1.0, cie), from);
+    assert_eq!(from.interpolate(to, f32::NAN, linear), from);
+
+    let halfway = from.interpolate(to, 0.5, linear);
+    assert_eq!((halfway.hue, halfway.saturation, halfway.brightness), (10000, 32768, 32768));
+    // Halfway in mireds is much warmer than halfway in Kelvin
+    assert_eq!(halfway.temperature, Kelvin(3913));
+
+    // Perceptually, half brightness is less than a fifth of the light
+    assert_eq!(from.interpolate(to, 0.5, cie).brightness, 12071);
+
+    // Hue wraps around the short way
+    let red = Hsbk { hue: 0, ..to };
+    let magenta = Hsbk { hue: 54613, ..to };
+    assert_eq!(red.interpolate(magenta, 0.5, linear).hue, 60074);
+    assert_eq!(magenta.interpolate(red, 0.5, linear).hue, 60075);
+    assert_eq!(magenta.interpolate(red, 1.0, linear).hue, 0);
+
+    // Unset temperatures fall back to linear blending
+    assert_eq!(Kelvin(0).interpolate(Kelvin(4000), 0.25), Kelvin(1000));
+}
+
+#[test]
+fn test_brightness_curves() {
+    let cie = BrightnessCurve::CieLightness;
+    // Reference values of L* for relative luminance, from 0 to 100. Rounding both to 16 bits loses a little precision.
+    for (luminance, lightness) in [(0.0, 0.0), (0.5, 4.5165), (1.0, 8.9966), (18.42, 50.0), (50.0, 76.0693), (100.0, 100.0)].iter().copied() {
+        let brightness = scale_unit(luminance / 100.0);
+        let lightness = scale_unit(lightness / 100.0);
+        assert!(cie.to_lightness(brightness).abs_diff(lightness) <= 8, "L* of {}", luminance);
+        assert!(cie.to_brightness(lightness).abs_diff(brightness) <= 8, "luminance of L* {}", lightness);
+    }
+
+    let lut = BrightnessCurve::Lut(Lut::new(vec![0, 1000, 1000, 65535]).unwrap());
+    assert_eq!(lut.to_brightness(0), 0);
+    assert_eq!(lut.to_brightness(10923), 500);
+    assert_eq!(lut.to_brightness(30000), 1000);
+    assert_eq!(lut.to_brightness(65535), 65535);
+    assert_eq!(lut.to_lightness(500), 10923);
+    // Flat sections map back to their lowest lightness
+    assert_eq!(lut.to_lightness(1000), 21845);
+    assert_eq!(lut.to_lightness(65535), 65535);
+
+    let gamma = BrightnessCurve::Gamma(Gamma::new(2.2).unwrap());
+    assert_eq!(gamma.to_brightness(0), 0);
+    // 0.5^2.2 = 0.217638
+    assert_eq!(gamma.to_brightness(32768), 14263);
+    assert_eq!(gamma.to_lightness(14263), 32768);
+    assert_eq!(gamma.to_brightness(65535), 65535);
+    for invalid in [0.0, -1.0, f32::NAN, f32::INFINITY].iter().copied() {
+        assert!(Gamma::new(invalid).is_err());
+    }
+
+    assert_eq!(Lut::new(vec![0, 100, 50]), Err(LutError(())));
+    assert_eq!(Lut::new(vec![0]), Err(LutError(())));
+    assert_eq!(Lut::new(vec![5, 6]).unwrap().as_slice(), &[5, 6]);
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_hsbk_roundtrip(color: Hsbk) {
+        let mut encoded = Vec::new();
+        color.encode(&mut encoded);
+        proptest::prop_assert_eq!(encoded.len(), Hsbk::SIZE);
+
+        let decoded = Hsbk::decode(&mut encoded.as_slice()).unwrap();
+        proptest::prop_assert_eq!(decoded, color);
+    }
+
+    #[test]
+    fn test_brightness_curve_roundtrip(lightness: u16, mut table in proptest::collection::vec(proptest::num::u16::ANY, 2..10)) {
+        table.sort_unstable();
+        let curves = [
+            BrightnessCurve::Linear,
+            BrightnessCurve::CieLightness,
+            BrightnessCurve::Gamma(Gamma::new(2.2).unwrap()),
+            BrightnessCurve::Gamma(Gamma::new(0.45).unwrap()),
+            BrightnessCurve::Lut(Lut::new(table).unwrap()),
+        ];
+
+        for curve in &curves {
+            // Lightness has more resolution than brightness near black, so compare brightnesses instead
+            let brightness = curve.to_brightness(lightness);
+            let roundtrip = curve.to_brightness(curve.to_lightness(brightness));
+            proptest::prop_assert!(roundtrip.abs_diff(brightness) <= 1, "{:?}: {} became {}", curve, brightness, roundtrip);
+        }
+    }
+}
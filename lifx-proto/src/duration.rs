@@ -0,0 +1,108 @@
+//! Durations as the protocol represents them: a 32-bit number of milliseconds
+
+use core::convert::TryFrom;
+use core::fmt;
+use core::time::Duration;
+
+use bytes::{Buf, BufMut};
+
+use crate::ProtocolError;
+
+/// A duration that fits in a protocol duration field, such as a color transition time.
+///
+/// Fields are a `u32` number of milliseconds, so durations are limited to [`ProtocolDuration::MAX`], about 49.7 days, and anything shorter than a
+/// millisecond is rounded down. Converting a [`Duration`] with [`TryFrom`] rejects durations that are too long, and
+/// [`ProtocolDuration::saturating`] caps them instead.
+///
+/// ```
+/// use std::convert::TryFrom;
+/// use std::time::Duration;
+/// use lifx_proto::duration::ProtocolDuration;
+///
+/// let transition = ProtocolDuration::try_from(Duration::from_secs(2)).unwrap();
+/// assert_eq!(transition.as_millis(), 2000);
+///
+/// let forever = Duration::from_secs(60 * 60 * 24 * 365);
+/// assert!(ProtocolDuration::try_from(forever).is_err());
+/// assert_eq!(ProtocolDuration::saturating(forever), ProtocolDuration::MAX);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(from = "u32", into = "u32"))]
+pub struct ProtocolDuration(u32);
+
+impl ProtocolDuration {
+    /// Size of a duration on the wire, in bytes
+    pub const SIZE: usize = 4;
+
+    pub const ZERO: ProtocolDuration = ProtocolDuration(0);
+    pub const MAX: ProtocolDuration = ProtocolDuration(u32::MAX);
+
+    pub const fn from_millis(millis: u32) -> ProtocolDuration {
+        ProtocolDuration(millis)
+    }
+
+    /// Convert `duration`, capping it at [`ProtocolDuration::MAX`]
+    pub fn saturating(duration: Duration) -> ProtocolDuration {
+        ProtocolDuration::try_from(duration).unwrap_or(ProtocolDuration::MAX)
+    }
+
+    pub const fn as_millis(self) -> u32 {
+        self.0
+    }
+
+    pub const fn as_duration(self) -> Duration {
+        Duration::from_millis(self.0 as u64)
+    }
+
+    pub fn encode<B: BufMut>(self, buf: &mut B) {
+        buf.put_u32_le(self.0);
+    }
+
+    pub fn decode<B: Buf>(buf: &mut B) -> ProtocolDuration {
+        ProtocolDuration(buf.get_u32_le())
+    }
+}
+
+/// Fails with [`ProtocolError::DurationOutOfRange`] if `duration` is longer than [`ProtocolDuration::MAX`]
+impl TryFrom<Duration> for ProtocolDuration {
+    type Error = ProtocolError;
+
+    fn try_from(duration: Duration) -> Result<ProtocolDuration, ProtocolError> {
+        u32::try_from(duration.as_millis()).map(ProtocolDuration).map_err(|_| ProtocolError::DurationOutOfRange(duration))
+    }
+}
+
+impl From<u32> for ProtocolDuration {
+    fn from(millis: u32) -> ProtocolDuration {
+        ProtocolDuration(millis)
+    }
+}
+
+impl From<ProtocolDuration> for u32 {
+    fn from(duration: ProtocolDuration) -> u32 {
+        duration.0
+    }
+}
+
+impl From<ProtocolDuration> for Duration {
+    fn from(duration: ProtocolDuration) -> Duration {
+        duration.as_duration()
+    }
+}
+
+impl fmt::Display for ProtocolDuration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}ms", self.0)
+    }
+}
+
+#[test]
+fn test_conversions() {
+    assert_eq!(ProtocolDuration::try_from(Duration::from_micros(1500)).unwrap(), ProtocolDuration::from_millis(1));
+    assert_eq!(ProtocolDuration::try_from(Duration::from_millis(u32::MAX.into())).unwrap(), ProtocolDuration::MAX);
+
+    let too_long = Duration::from_millis(u64::from(u32::MAX) + 1);
+    assert!(matches!(ProtocolDuration::try_from(too_long), Err(ProtocolError::DurationOutOfRange(d)) if d == too_long));
+    assert_eq!(ProtocolDuration::saturating(too_long), ProtocolDuration::MAX);
+    assert_eq!(ProtocolDuration::saturating(Duration::from_secs(3)).as_duration(), Duration::from_secs(3));
+}
@@ -1,6 +1,6 @@
 //! On-the-wire representations of the LIFX LAN protocol
 
-use std::fmt;
+use core::fmt;
 
 use bit_field::BitField;
 use bytes::{Buf, BufMut};
@@ -15,6 +15,7 @@ use crate::{ProtocolError, message::MessageType};
 ///
 /// See the [header description documentation](https://lan.developer.lifx.com/docs/header-description).
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Header {
     /// Size of the entire message in bytes.
     pub size: u16,
@@ -41,7 +42,7 @@ pub struct Header {
 /// Address of the device(s) a message is being sent to/from.
 ///
 /// This corresponds to the `tagged` field of the Frame section and the `target` field of the Frame Address section.
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum DeviceTarget {
     All,
     Targeted(MacAddr6),
@@ -60,6 +61,10 @@ impl Header {
 
     /// Deserialize a message header from an input buffer.
     pub fn decode<B: Buf>(buf: &mut B) -> Result<Header, ProtocolError> {
+        if buf.remaining() < Header::HEADER_SIZE {
+            return Err(ProtocolError::TooShort { expected: Header::HEADER_SIZE, actual: buf.remaining() });
+        }
+
         // Parse the Frame header
 
         let size = buf.get_u16_le();
@@ -134,10 +139,10 @@ impl Header {
 
         // 8 bytes for the target
         match self.target {
-            DeviceTarget::All => buf.put_u64(0),
+            DeviceTarget::All => buf.put_u64_le(0),
             DeviceTarget::Targeted(target) => {
                 buf.put_slice(target.as_bytes());
-                buf.put_u16(0); // Last 2 bytes are 0
+                buf.put_u16_le(0); // Last 2 bytes are 0
             }
         }
 
@@ -152,9 +157,9 @@ impl Header {
 
         // Write the Protocol Header
 
-        buf.put_u64(0); // 8 bytes of padding
+        buf.put_u64_le(0); // 8 bytes of padding
         buf.put_u16_le(self.message_type.into());
-        buf.put_u16(0); // 2 bytes of padding
+        buf.put_u16_le(0); // 2 bytes of padding
     }
 
     /// The expected size of the payload following this header, in bytes
@@ -173,7 +178,34 @@ impl fmt::Display for DeviceTarget {
     }
 }
 
+/// Targets are serialized as either `"all"` or a lowercase, colon-separated MAC address such as `"d0:73:d5:01:02:03"`
+#[cfg(feature = "serde")]
+impl serde::Serialize for DeviceTarget {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            DeviceTarget::All => serializer.serialize_str("all"),
+            DeviceTarget::Targeted(addr) => {
+                let [a, b, c, d, e, f] = addr.into_array();
+                serializer.collect_str(&format_args!("{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, f))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for DeviceTarget {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<DeviceTarget, D::Error> {
+        let value = alloc::string::String::deserialize(deserializer)?;
+        if value == "all" {
+            Ok(DeviceTarget::All)
+        } else {
+            value.parse().map(DeviceTarget::Targeted).map_err(serde::de::Error::custom)
+        }
+    }
+}
+
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn test_header() {
     let mut bytes: &[u8] = &[0b0000_0000, 0b0011_0100];
     let proto_flags = bytes.get_u16_le();
@@ -187,3 +219,16 @@ fn test_header() {
     // Tagged
     assert_eq!(proto_flags.get_bit(13), true);
 }
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_header_roundtrip(header: Header) {
+        let mut encoded = Vec::new();
+        header.encode(&mut encoded);
+        proptest::prop_assert_eq!(encoded.len(), Header::HEADER_SIZE);
+
+        let decoded = Header::decode(&mut encoded.as_slice()).unwrap();
+        proptest::prop_assert_eq!(decoded, header);
+    }
+}
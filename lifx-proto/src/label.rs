@@ -1,14 +1,24 @@
 //! LIFX protocol labels. Labels are 32-byte UTF-8 strings
 
-use std::convert::TryFrom;
-use std::fmt;
+use alloc::string::{String, ToString};
+use core::convert::TryFrom;
+use core::{fmt, str};
 
 use bytes::{Buf, BufMut};
 
 use crate::ProtocolError;
 
-#[derive(Debug, Clone, Eq, PartialEq)]
-pub struct Label(String);
+/// A label, stored inline in the same fixed-size buffer devices use.
+///
+/// Devices don't validate labels, so one set by another client may not be valid UTF-8. [`Label::decode_lossy`] accepts these labels
+/// and keeps their raw bytes, so they're re-encoded exactly as they were received.
+#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(try_from = "String", into = "String"))]
+pub struct Label {
+    // Always valid UTF-8 up to `len`. After that, it's NUL padding, unless the label was decoded from invalid UTF-8.
+    bytes: [u8; Label::MAX_LENGTH],
+    len: u8,
+}
 
 impl Label {
     pub const MAX_LENGTH: usize = 32;
@@ -17,55 +27,185 @@ impl Label {
     ///
     /// # Panics
     /// If `str` is longer than [`Label::MAX_LENGTH`]
-    pub fn new<S: Into<String>>(str: S) -> Label {
-        Label::try_from(str.into()).unwrap()
+    pub fn new<S: AsRef<str>>(str: S) -> Label {
+        Label::try_from(str.as_ref()).unwrap()
     }
 
-    pub fn encode<B: BufMut>(&self, buf: &mut B) {
-        buf.put_slice(self.0.as_bytes());
-        for _ in 0..(Label::MAX_LENGTH - self.0.len()) {
-            buf.put_u8(0);
+    /// Create a new `Label` from as much of `str` as fits. If `str` is too long, it's cut off at the last character boundary that fits, so
+    /// multi-byte characters are never split.
+    pub fn truncate<S: AsRef<str>>(str: S) -> Label {
+        let str = str.as_ref();
+        let mut len = str.len().min(Label::MAX_LENGTH);
+        while !str.is_char_boundary(len) {
+            len -= 1;
         }
+        Label::new(&str[..len])
     }
 
-    pub fn decode<B: Buf>(buf: &mut B) -> Result<Label, ProtocolError> {
-        let mut str_bytes = Vec::with_capacity(Label::MAX_LENGTH);
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&self.bytes);
+    }
 
-        while str_bytes.len() < Label::MAX_LENGTH {
-            let to_consume = Label::MAX_LENGTH - str_bytes.len();
-            str_bytes.extend_from_slice(&buf.chunk()[..to_consume]);
-            buf.advance(to_consume);
+    /// Decode a label, failing with [`ProtocolError::InvalidLabel`] if it isn't valid UTF-8.
+    ///
+    /// # Panics
+    /// If `buf` has fewer than [`Label::MAX_LENGTH`] bytes remaining
+    pub fn decode<B: Buf>(buf: &mut B) -> Result<Label, ProtocolError> {
+        let label = Label::decode_lossy(buf);
+        if label.is_valid() {
+            Ok(label)
+        } else {
+            Err(ProtocolError::InvalidLabel)
         }
+    }
 
-        match String::from_utf8(str_bytes) {
-            Ok(str) => Ok(Label(str.trim_end_matches(char::from(0)).into())),
-            Err(_) => Err(ProtocolError::InvalidLabel),
-        }
+    /// Decode a label, accepting invalid UTF-8. The label's text is everything before the first invalid byte, but the raw bytes are kept
+    /// so that encoding the label reproduces them exactly.
+    ///
+    /// # Panics
+    /// If `buf` has fewer than [`Label::MAX_LENGTH`] bytes remaining
+    pub fn decode_lossy<B: Buf>(buf: &mut B) -> Label {
+        let mut bytes = [0u8; Label::MAX_LENGTH];
+        // Unlike reading from `chunk()`, this works when the bytes are split across several chunks
+        buf.copy_to_slice(&mut bytes);
+
+        let content = bytes.iter().rposition(|b| *b != 0).map_or(0, |last| last + 1);
+        let len = match str::from_utf8(&bytes[..content]) {
+            Ok(_) => content,
+            Err(err) => err.valid_up_to(),
+        };
+        Label { bytes, len: len as u8 }
     }
 
+    /// The label's text. For labels decoded from invalid UTF-8, this stops before the first invalid byte, so use [`Label::to_string_lossy`]
+    /// or `Display` to see the rest.
     pub fn as_str(&self) -> &str {
-        self.0.as_str()
+        str::from_utf8(&self.bytes[..self.len as usize]).expect("Label is not valid UTF-8")
+    }
+
+    /// The label's raw bytes, including any NUL padding
+    pub fn as_bytes(&self) -> &[u8; Label::MAX_LENGTH] {
+        &self.bytes
+    }
+
+    /// Whether the label is valid UTF-8. Only labels from [`Label::decode_lossy`] can be invalid.
+    pub fn is_valid(&self) -> bool {
+        self.bytes[self.len as usize..].iter().all(|b| *b == 0)
+    }
+
+    /// The label's text, with invalid UTF-8 replaced by `U+FFFD REPLACEMENT CHARACTER`
+    pub fn to_string_lossy(&self) -> String {
+        self.to_string()
     }
 
+    /// Convert to a `String`. Like [`Label::to_string_lossy`], invalid UTF-8 is replaced.
     pub fn into_string(self) -> String {
-        self.0
+        self.to_string_lossy()
     }
 }
 
-impl TryFrom<String> for Label {
+impl TryFrom<&str> for Label {
     type Error = ProtocolError;
 
-    fn try_from(value: String) -> Result<Label, ProtocolError> {
+    fn try_from(value: &str) -> Result<Label, ProtocolError> {
         if value.len() <= Label::MAX_LENGTH {
-            Ok(Label(value))
+            let mut bytes = [0u8; Label::MAX_LENGTH];
+            bytes[..value.len()].copy_from_slice(value.as_bytes());
+            Ok(Label { bytes, len: value.len() as u8 })
         } else {
             Err(ProtocolError::InvalidLabel)
         }
     }
 }
 
+impl TryFrom<String> for Label {
+    type Error = ProtocolError;
+
+    fn try_from(value: String) -> Result<Label, ProtocolError> {
+        Label::try_from(value.as_str())
+    }
+}
+
+impl From<Label> for String {
+    fn from(label: Label) -> String {
+        label.into_string()
+    }
+}
+
+impl fmt::Debug for Label {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_valid() {
+            f.debug_tuple("Label").field(&self.as_str()).finish()
+        } else {
+            f.debug_tuple("Label").field(&self.to_string_lossy()).finish()
+        }
+    }
+}
+
+/// Formats the label's text, replacing invalid UTF-8 with `U+FFFD REPLACEMENT CHARACTER`
 impl fmt::Display for Label {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.0.fmt(f)
+        if self.is_valid() {
+            return self.as_str().fmt(f);
+        }
+
+        let content = self.bytes.iter().rposition(|b| *b != 0).map_or(0, |last| last + 1);
+        String::from_utf8_lossy(&self.bytes[..content]).fmt(f)
+    }
+}
+
+#[test]
+fn test_truncate() {
+    assert_eq!(Label::truncate("Kitchen").as_str(), "Kitchen");
+    assert_eq!(Label::truncate("x".repeat(40)).as_str(), "x".repeat(32));
+
+    // "é" is 2 bytes, so it can't straddle the limit
+    let accented = format!("{}é", "x".repeat(31));
+    assert_eq!(Label::truncate(&accented).as_str(), "x".repeat(31));
+    let emoji = format!("ab{}", "💡".repeat(10));
+    assert_eq!(Label::truncate(&emoji).as_str(), format!("ab{}", "💡".repeat(7)));
+}
+
+#[test]
+fn test_decode_lossy() {
+    let mut raw = [0u8; Label::MAX_LENGTH];
+    raw[..9].copy_from_slice(b"Bed\xffroom\xc3");
+
+    assert!(matches!(Label::decode(&mut &raw[..]), Err(ProtocolError::InvalidLabel)));
+    let label = Label::decode_lossy(&mut &raw[..]);
+    assert!(!label.is_valid());
+    assert_eq!(label.as_str(), "Bed");
+    assert_eq!(label.to_string_lossy(), "Bed\u{FFFD}room\u{FFFD}");
+    assert_eq!(format!("{:?}", label), "Label(\"Bed\u{FFFD}room\u{FFFD}\")");
+
+    // The raw bytes survive re-encoding
+    let mut encoded = Vec::new();
+    label.encode(&mut encoded);
+    assert_eq!(encoded, raw);
+    assert_eq!(label.as_bytes(), &raw);
+}
+
+#[test]
+fn test_decode_chunked() {
+    // The label is split across two chunks, which used to panic
+    let label = Label::new("Living room lamp");
+    let mut encoded = Vec::new();
+    label.encode(&mut encoded);
+    let (first, second) = encoded.split_at(5);
+    let mut buf = first.chain(second);
+    assert_eq!(Label::decode(&mut buf).unwrap(), label);
+    assert_eq!(buf.remaining(), 0);
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_label_roundtrip(label: Label) {
+        let mut encoded = Vec::new();
+        label.encode(&mut encoded);
+        proptest::prop_assert_eq!(encoded.len(), Label::MAX_LENGTH);
+
+        let decoded = Label::decode(&mut encoded.as_slice()).unwrap();
+        proptest::prop_assert_eq!(decoded, label);
     }
 }
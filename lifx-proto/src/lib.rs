@@ -1,40 +1,74 @@
 //! Representation of the LIFX LAN protocol
+//!
+//! This crate only needs `core` and `alloc`, so it can be used on embedded targets by disabling the default `std` feature.
 
-use std::convert::TryInto;
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+use alloc::string::String;
+use core::convert::TryInto;
+use core::fmt;
+use core::time::Duration;
 
 use bytes::{Buf, BufMut};
-use thiserror::Error;
 
 pub mod color;
+pub mod duration;
 pub mod label;
+mod math;
 pub mod message;
+pub mod products;
 pub mod header;
+pub mod query;
+pub mod rgb;
+pub mod view;
+
+#[cfg(any(test, feature = "proptest"))]
+mod arbitrary;
 
-pub use message::{Message, MessageType, Service};
+pub use message::{Message, MessageRef, MessageType, Service};
 pub use header::{DeviceTarget, Header};
+pub use products::Product;
+pub use query::Query;
+pub use view::PacketRef;
 
-#[derive(Debug, Error)]
+#[derive(Debug)]
 pub enum ProtocolError {
-    #[error("unexpected message: {0:?}")]
     UnexpectedMessage(MessageType),
-
-    #[error("invalid protocol number: {0}")]
     InvalidProtocol(u16),
-
-    #[error("message not marked as addressable")]
     NotAddressable,
-
-    #[error("invalid origin indicator: {0}")]
     InvalidOrigin(u8),
-
-    #[error("invalid label")]
     InvalidLabel,
-
-    #[error("invalid payload: {0}")]
     InvalidPayload(String),
+    TooShort { expected: usize, actual: usize },
+    /// A duration too long to fit in a protocol duration field
+    DurationOutOfRange(Duration),
+    /// The buffer a packet was being encoded into doesn't have room for it
+    BufferTooSmall { needed: usize, available: usize },
 }
 
-#[derive(Debug, Clone)]
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::UnexpectedMessage(message_type) => write!(f, "unexpected message: {:?}", message_type),
+            ProtocolError::InvalidProtocol(protocol) => write!(f, "invalid protocol number: {}", protocol),
+            ProtocolError::NotAddressable => f.write_str("message not marked as addressable"),
+            ProtocolError::InvalidOrigin(origin) => write!(f, "invalid origin indicator: {}", origin),
+            ProtocolError::InvalidLabel => f.write_str("invalid label"),
+            ProtocolError::InvalidPayload(reason) => write!(f, "invalid payload: {}", reason),
+            ProtocolError::TooShort { expected, actual } => write!(f, "expected at least {} bytes, got {}", expected, actual),
+            ProtocolError::DurationOutOfRange(duration) => write!(f, "duration of {:?} is longer than the protocol allows", duration),
+            ProtocolError::BufferTooSmall { needed, available } => write!(f, "packet needs {} bytes, but only {} are available", needed, available),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ProtocolError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Packet {
     source: u32,
     target: DeviceTarget,
@@ -56,19 +90,23 @@ impl Packet {
         }
     }
 
-    pub fn encode<B: BufMut>(&self, buf: &mut B) {
-        let size = self.len().try_into().expect("Packet size larger than u16");
-        let header = Header {
-            size,
-            source: self.source,
-            target: self.target,
-            sequence: self.sequence,
-            response_required: self.response_required,
-            acknowledgement_required: self.acknowledgement_required,
-            message_type: self.message.message_type(),
-        };
-        header.encode(buf);
+    /// Start building a packet containing `message`
+    pub fn builder<M: Into<Message>>(message: M) -> PacketBuilder {
+        PacketBuilder::new(message)
+    }
+
+    /// Encode the packet into `buf`. If `buf` can't hold the whole packet, this fails with [`ProtocolError::BufferTooSmall`] before
+    /// writing anything, rather than leaving a partial packet behind.
+    pub fn encode<B: BufMut>(&self, buf: &mut B) -> Result<(), ProtocolError> {
+        let needed = self.len();
+        let available = buf.remaining_mut();
+        if available < needed {
+            return Err(ProtocolError::BufferTooSmall { needed, available });
+        }
+
+        self.header().encode(buf);
         self.message.encode_payload(buf);
+        Ok(())
     }
 
     pub fn decode<B: Buf>(buf: &mut B) -> Result<Packet, ProtocolError> {
@@ -84,10 +122,24 @@ impl Packet {
         })
     }
 
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
         Header::HEADER_SIZE + self.message.payload_size()
     }
 
+    /// The header this packet is sent with
+    pub fn header(&self) -> Header {
+        Header {
+            size: self.len().try_into().expect("Packet size larger than u16"),
+            source: self.source,
+            target: self.target,
+            sequence: self.sequence,
+            response_required: self.response_required,
+            acknowledgement_required: self.acknowledgement_required,
+            message_type: self.message.message_type(),
+        }
+    }
+
     pub fn source(&self) -> u32 {
         self.source
     }
@@ -100,6 +152,14 @@ impl Packet {
         self.target
     }
 
+    pub fn response_required(&self) -> bool {
+        self.response_required
+    }
+
+    pub fn acknowledgement_required(&self) -> bool {
+        self.acknowledgement_required
+    }
+
     pub fn message(&self) -> &Message {
         &self.message
     }
@@ -108,3 +168,132 @@ impl Packet {
         self.message
     }
 }
+
+/// Builder for [`Packet`]s. Packets are sent from source 0 to all devices with sequence number 0 and no responses required, unless configured otherwise.
+///
+/// ```
+/// use lifx_proto::{DeviceTarget, Packet};
+/// use lifx_proto::message::GetLabel;
+///
+/// let packet = Packet::builder(GetLabel)
+///     .with_source(1234)
+///     .with_target(DeviceTarget::Targeted([0xd0, 0x73, 0xd5, 0x01, 0x02, 0x03].into()))
+///     .with_response_required(true)
+///     .build();
+/// assert!(packet.response_required());
+/// assert!(!packet.acknowledgement_required());
+/// ```
+#[derive(Debug, Clone)]
+pub struct PacketBuilder {
+    packet: Packet,
+}
+
+impl PacketBuilder {
+    pub fn new<M: Into<Message>>(message: M) -> PacketBuilder {
+        PacketBuilder {
+            packet: Packet::new(0, DeviceTarget::All, 0, false, false, message.into()),
+        }
+    }
+
+    /// Set the source identifier. Devices only send responses to the client that sent the original message if this is non-zero.
+    pub fn with_source(mut self, source: u32) -> PacketBuilder {
+        self.packet.source = source;
+        self
+    }
+
+    pub fn with_target(mut self, target: DeviceTarget) -> PacketBuilder {
+        self.packet.target = target;
+        self
+    }
+
+    pub fn with_sequence(mut self, sequence: u8) -> PacketBuilder {
+        self.packet.sequence = sequence;
+        self
+    }
+
+    /// Ask the device to reply with a response message
+    pub fn with_response_required(mut self, response_required: bool) -> PacketBuilder {
+        self.packet.response_required = response_required;
+        self
+    }
+
+    /// Ask the device to send an acknowledgement message
+    pub fn with_acknowledgement_required(mut self, acknowledgement_required: bool) -> PacketBuilder {
+        self.packet.acknowledgement_required = acknowledgement_required;
+        self
+    }
+
+    pub fn source(&self) -> u32 {
+        self.packet.source
+    }
+
+    pub fn target(&self) -> DeviceTarget {
+        self.packet.target
+    }
+
+    pub fn sequence(&self) -> u8 {
+        self.packet.sequence
+    }
+
+    pub fn response_required(&self) -> bool {
+        self.packet.response_required
+    }
+
+    pub fn acknowledgement_required(&self) -> bool {
+        self.packet.acknowledgement_required
+    }
+
+    pub fn message(&self) -> &Message {
+        &self.packet.message
+    }
+
+    pub fn build(self) -> Packet {
+        self.packet
+    }
+}
+
+#[test]
+fn test_encode_buffer_too_small() {
+    let packet = Packet::builder(message::GetLabel).build();
+    let mut buf = [0u8; Header::HEADER_SIZE - 1];
+    let result = packet.encode(&mut &mut buf[..]);
+    assert!(matches!(result, Err(ProtocolError::BufferTooSmall { needed: Header::HEADER_SIZE, available }) if available == Header::HEADER_SIZE - 1));
+    // Nothing was written
+    assert!(buf.iter().all(|b| *b == 0));
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_builder(packet: Packet) {
+        let built = Packet::builder(packet.message().clone())
+            .with_source(packet.source())
+            .with_target(packet.target())
+            .with_sequence(packet.sequence())
+            .with_response_required(packet.response_required())
+            .with_acknowledgement_required(packet.acknowledgement_required())
+            .build();
+        proptest::prop_assert_eq!(built, packet);
+    }
+
+    #[test]
+    fn test_packet_roundtrip(packet: Packet) {
+        let mut encoded = Vec::new();
+        packet.encode(&mut encoded).unwrap();
+        proptest::prop_assert_eq!(encoded.len(), packet.len());
+
+        let decoded = Packet::decode(&mut encoded.as_slice()).unwrap();
+        proptest::prop_assert_eq!(decoded, packet);
+    }
+
+    #[test]
+    fn test_truncated_packet(packet: Packet, len: proptest::sample::Index) {
+        let mut encoded = Vec::new();
+        packet.encode(&mut encoded).unwrap();
+
+        // Every strict prefix is rejected rather than read past the end
+        let prefix = &encoded[..len.index(encoded.len())];
+        let result = Packet::decode(&mut &prefix[..]);
+        proptest::prop_assert!(matches!(result, Err(ProtocolError::TooShort { .. })), "{:?}", result);
+    }
+}
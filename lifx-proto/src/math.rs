@@ -0,0 +1,34 @@
+//! Float helpers shared by the color conversions. Most float functions aren't in `core`, so without `std` they come from `libm`.
+
+/// Scale a LIFX component to the range 0 to 1
+pub(crate) fn unit(value: u16) -> f32 {
+    f32::from(value) / 65535.0
+}
+
+/// Scale a value from 0 to 1 to a LIFX component, rounding to the nearest value. Out-of-range values are clamped.
+pub(crate) fn scale_unit(value: f32) -> u16 {
+    // Float to int casts saturate, so this only needs to handle rounding
+    (value * 65535.0 + 0.5) as u16
+}
+
+/// Round to the nearest integer, with halves away from zero
+pub(crate) fn round(value: f32) -> f32 {
+    #[cfg(feature = "std")]
+    return value.round();
+    #[cfg(not(feature = "std"))]
+    return libm::roundf(value);
+}
+
+pub(crate) fn cbrt(value: f32) -> f32 {
+    #[cfg(feature = "std")]
+    return value.cbrt();
+    #[cfg(not(feature = "std"))]
+    return libm::cbrtf(value);
+}
+
+pub(crate) fn powf(base: f32, exponent: f32) -> f32 {
+    #[cfg(feature = "std")]
+    return base.powf(exponent);
+    #[cfg(not(feature = "std"))]
+    return libm::powf(base, exponent);
+}
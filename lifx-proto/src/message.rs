@@ -1,235 +1,485 @@
-use std::time::Duration;
-
 use bytes::{BufMut, Buf};
 
 use crate::ProtocolError;
 use crate::color::Hsbk;
+use crate::duration::ProtocolDuration;
 use crate::header::Header;
 use crate::label::Label;
+use crate::products::{FirmwareVersion, Product};
+use crate::view::PayloadRef;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Message {
-    // Device messages
-    GetService,
-    StateService(StateService),
-    GetLabel,
-    SetLabel(SetLabel),
-    StateLabel(StateLabel),
+/// Wire format of a message payload
+pub trait Payload: Sized {
+    /// Size of the payload on the wire, in bytes
+    const SIZE: usize;
 
-    Acknowledgement,
+    fn encode<B: BufMut>(&self, buf: &mut B);
 
-    // Light messages
-    Get,
-    SetColor(SetColor),
-    State(State),
+    /// Decode a payload, failing with [`ProtocolError::TooShort`] if `buf` has fewer than [`Payload::SIZE`] bytes remaining
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self, ProtocolError>;
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-pub enum MessageType {
-    GetService,
-    StateService,
+/// Check that `buf` holds a whole `P` payload, since reading past the end of a `Buf` panics
+fn check_size<P: Payload, B: Buf>(buf: &B) -> Result<(), ProtocolError> {
+    if buf.remaining() < P::SIZE {
+        Err(ProtocolError::TooShort { expected: P::SIZE, actual: buf.remaining() })
+    } else {
+        Ok(())
+    }
+}
+
+/// Defines the [`Message`] and [`MessageType`] enums from a table of messages.
+///
+/// Each entry gives the message's type number and name, and the type of its payload if it has one. Payload types must implement [`Payload`]. Messages
+/// without a payload get a unit struct of the same name, so that every message has a type that can be converted into a [`Message`].
+macro_rules! messages {
+    ($($(#[$meta:meta])* $id:literal => $name:ident $(($payload:ty))?,)*) => {
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        pub enum Message {
+            $($(#[$meta])* $name $(($payload))?,)*
+        }
+
+        #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        pub enum MessageType {
+            $($(#[$meta])* $name,)*
+            Other(u16),
+        }
+
+        /// A [`Message`] borrowed from an encoded packet. Payload fields are read from the packet as they're accessed.
+        #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+        pub enum MessageRef<'a> {
+            $($(#[$meta])* $name $((PayloadRef<'a, $payload>))?,)*
+        }
+
+        $(messages!(@struct $name $(, $payload)?);)*
+
+        impl Message {
+            pub fn message_type(&self) -> MessageType {
+                match self {
+                    $(messages!(@pattern $name $(, $payload)?) => MessageType::$name,)*
+                }
+            }
+
+            pub fn payload_size(&self) -> usize {
+                match self {
+                    $(messages!(@pattern $name $(, $payload)?) => messages!(@size $(, $payload)?),)*
+                }
+            }
+
+            pub(crate) fn encode_payload<B: BufMut>(&self, buf: &mut B) {
+                match self {
+                    $(messages!(@bind $name $(, $payload)?; inner) => messages!(@encode buf $(, $payload)?; inner),)*
+                }
+            }
+
+            pub(crate) fn decode<B: Buf>(header: &Header, buf: &mut B) -> Result<Message, ProtocolError> {
+                match header.message_type {
+                    $(MessageType::$name => Ok(messages!(@decode $name buf $(, $payload)?)),)*
+                    MessageType::Other(_) => Err(ProtocolError::UnexpectedMessage(header.message_type)),
+                }
+            }
+        }
+
+        impl MessageType {
+            /// Size of this type's payload on the wire, in bytes, if it's a known message type
+            pub fn payload_size(self) -> Option<usize> {
+                match self {
+                    $(MessageType::$name => Some(messages!(@size $(, $payload)?)),)*
+                    MessageType::Other(_) => None,
+                }
+            }
+        }
+
+        impl<'a> MessageRef<'a> {
+            /// Borrow a message of type `message_type` from its encoded payload. Fails if the payload is too short for the message type.
+            pub fn new(message_type: MessageType, payload: &'a [u8]) -> Result<MessageRef<'a>, ProtocolError> {
+                match message_type {
+                    $(MessageType::$name => Ok(messages!(@view $name payload $(, $payload)?)),)*
+                    MessageType::Other(_) => Err(ProtocolError::UnexpectedMessage(message_type)),
+                }
+            }
+
+            pub fn message_type(&self) -> MessageType {
+                match self {
+                    $(messages!(@ref_pattern $name $(, $payload)?) => MessageType::$name,)*
+                }
+            }
+
+            /// Decode the full, owned [`Message`]
+            pub fn to_message(&self) -> Result<Message, ProtocolError> {
+                match self {
+                    $(messages!(@ref_bind $name $(, $payload)?; inner) => Ok(messages!(@to_message $name $(, $payload)?; inner)),)*
+                }
+            }
+        }
+
+        impl From<u16> for MessageType {
+            fn from(value: u16) -> MessageType {
+                match value {
+                    $($id => MessageType::$name,)*
+                    _ => MessageType::Other(value),
+                }
+            }
+        }
+
+        impl From<MessageType> for u16 {
+            fn from(message_type: MessageType) -> u16 {
+                match message_type {
+                    $(MessageType::$name => $id,)*
+                    MessageType::Other(value) => value,
+                }
+            }
+        }
+
+        #[cfg(any(test, feature = "proptest"))]
+        impl proptest::arbitrary::Arbitrary for Message {
+            type Parameters = ();
+            type Strategy = proptest::strategy::BoxedStrategy<Message>;
+
+            fn arbitrary_with(_: ()) -> Self::Strategy {
+                use proptest::prelude::*;
+                prop_oneof![$(messages!(@arbitrary $name $(, $payload)?)),*].boxed()
+            }
+        }
+    };
+
+    (@struct $name:ident) => {
+        #[doc = concat!("A `", stringify!($name), "` [`Message`], which has no payload")]
+        #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        pub struct $name;
+
+        impl From<$name> for Message {
+            fn from(_: $name) -> Message {
+                Message::$name
+            }
+        }
+    };
+    (@struct $name:ident, $payload:ty) => {
+        impl From<$payload> for Message {
+            fn from(inner: $payload) -> Message {
+                Message::$name(inner)
+            }
+        }
+    };
+
+    (@pattern $name:ident) => { Message::$name };
+    (@pattern $name:ident, $payload:ty) => { Message::$name(_) };
+
+    (@bind $name:ident; $inner:ident) => { Message::$name };
+    (@bind $name:ident, $payload:ty; $inner:ident) => { Message::$name($inner) };
+
+    (@size) => { 0 };
+    (@size , $payload:ty) => { <$payload as Payload>::SIZE };
+
+    (@encode $buf:ident; $inner:ident) => { () };
+    (@encode $buf:ident, $payload:ty; $inner:ident) => { $inner.encode($buf) };
+
+    (@decode $name:ident $buf:ident) => { Message::$name };
+    (@decode $name:ident $buf:ident, $payload:ty) => { Message::$name(<$payload as Payload>::decode($buf)?) };
+
+    (@ref_pattern $name:ident) => { MessageRef::$name };
+    (@ref_pattern $name:ident, $payload:ty) => { MessageRef::$name(_) };
 
-    GetLabel,
-    SetLabel,
-    StateLabel,
+    (@ref_bind $name:ident; $inner:ident) => { MessageRef::$name };
+    (@ref_bind $name:ident, $payload:ty; $inner:ident) => { MessageRef::$name($inner) };
 
-    Acknowledgement,
+    (@view $name:ident $payload_bytes:ident) => { MessageRef::$name };
+    (@view $name:ident $payload_bytes:ident, $payload:ty) => { MessageRef::$name(PayloadRef::new($payload_bytes)?) };
 
-    Get,
-    SetColor,
-    State,
+    (@to_message $name:ident; $inner:ident) => { Message::$name };
+    (@to_message $name:ident, $payload:ty; $inner:ident) => { Message::$name($inner.decode()?) };
 
-    Other(u16),
+    (@arbitrary $name:ident) => { Just(Message::$name) };
+    (@arbitrary $name:ident, $payload:ty) => { any::<$payload>().prop_map(Message::$name) };
 }
 
+messages! {
+    // Device messages
+    2 => GetService,
+    3 => StateService(StateService),
+    14 => GetHostFirmware,
+    15 => StateHostFirmware(StateHostFirmware),
+    23 => GetLabel,
+    24 => SetLabel(SetLabel),
+    25 => StateLabel(StateLabel),
+    32 => GetVersion,
+    33 => StateVersion(StateVersion),
+    45 => Acknowledgement,
+
+    // Light messages
+    101 => Get,
+    102 => SetColor(SetColor),
+    107 => State(State),
+}
 
 /// Payload of a `StateService` [`Message`]
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StateService {
     pub service: Service,
     pub port: u32,
 }
 
+impl Payload for StateService {
+    const SIZE: usize = 5;
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(self.service.into());
+        buf.put_u32_le(self.port);
+    }
+
+    fn decode<B: Buf>(buf: &mut B) -> Result<StateService, ProtocolError> {
+        check_size::<StateService, _>(buf)?;
+        let service = Service::from(buf.get_u8());
+        let port = buf.get_u32_le();
+        Ok(StateService { service, port })
+    }
+}
+
+/// Payload of a `StateHostFirmware` [`Message`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StateHostFirmware {
+    /// When the firmware was built, in nanoseconds since the Unix epoch
+    pub build: u64,
+    pub version: FirmwareVersion,
+}
+
+impl Payload for StateHostFirmware {
+    const SIZE: usize = 8 /* build */ + 8 /* reserved */ + 2 /* minor version */ + 2 /* major version */;
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u64_le(self.build);
+        buf.put_u64_le(0); // reserved
+        buf.put_u16_le(self.version.minor);
+        buf.put_u16_le(self.version.major);
+    }
+
+    fn decode<B: Buf>(buf: &mut B) -> Result<StateHostFirmware, ProtocolError> {
+        check_size::<StateHostFirmware, _>(buf)?;
+        let build = buf.get_u64_le();
+        let _ = buf.get_u64_le(); // reserved
+        let minor = buf.get_u16_le();
+        let major = buf.get_u16_le();
+        Ok(StateHostFirmware { build, version: FirmwareVersion::new(major, minor) })
+    }
+}
+
+/// Payload of a `StateVersion` [`Message`], which identifies the type of device
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StateVersion {
+    pub vendor: u32,
+    pub product: u32,
+}
+
+impl StateVersion {
+    /// Look up the device's product, if it's a known one
+    pub fn product(&self) -> Option<&'static Product> {
+        Product::lookup(self.vendor, self.product)
+    }
+}
+
+impl Payload for StateVersion {
+    const SIZE: usize = 4 /* vendor */ + 4 /* product */ + 4 /* reserved */;
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u32_le(self.vendor);
+        buf.put_u32_le(self.product);
+        buf.put_u32_le(0); // reserved
+    }
+
+    fn decode<B: Buf>(buf: &mut B) -> Result<StateVersion, ProtocolError> {
+        check_size::<StateVersion, _>(buf)?;
+        let vendor = buf.get_u32_le();
+        let product = buf.get_u32_le();
+        let _ = buf.get_u32_le(); // reserved
+        Ok(StateVersion { vendor, product })
+    }
+}
+
 /// Payload of a `SetLabel` [`Message`]
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SetLabel {
     pub label: Label,
 }
 
+impl Payload for SetLabel {
+    const SIZE: usize = Label::MAX_LENGTH;
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        self.label.encode(buf);
+    }
+
+    fn decode<B: Buf>(buf: &mut B) -> Result<SetLabel, ProtocolError> {
+        check_size::<SetLabel, _>(buf)?;
+        let label = Label::decode_lossy(buf);
+        Ok(SetLabel { label })
+    }
+}
+
 /// Payload of a `StateLabel` [`Message`]
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StateLabel {
     pub label: Label,
 }
 
+impl Payload for StateLabel {
+    const SIZE: usize = Label::MAX_LENGTH;
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        self.label.encode(buf);
+    }
+
+    fn decode<B: Buf>(buf: &mut B) -> Result<StateLabel, ProtocolError> {
+        check_size::<StateLabel, _>(buf)?;
+        let label = Label::decode_lossy(buf);
+        Ok(StateLabel { label })
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SetColor {
     pub color: Hsbk,
     /// Color transition time
-    pub duration: Duration,
+    pub duration: ProtocolDuration,
+}
+
+impl Payload for SetColor {
+    const SIZE: usize = 1 /* reserved */ + Hsbk::SIZE + 4 /* duration */;
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(0); // reserved
+        self.color.encode(buf);
+        self.duration.encode(buf);
+    }
+
+    fn decode<B: Buf>(buf: &mut B) -> Result<SetColor, ProtocolError> {
+        check_size::<SetColor, _>(buf)?;
+        let _ = buf.get_u8(); // reserved
+        let color = Hsbk::decode(buf)?;
+        let duration = ProtocolDuration::decode(buf);
+        Ok(SetColor { color, duration })
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct State {
     pub color: Hsbk,
     pub power: u16,
     pub label: Label,
 }
 
+impl Payload for State {
+    const SIZE: usize = Hsbk::SIZE + 2 /* reserved */ + 2 /* power */ + Label::MAX_LENGTH + 8 /* reserved */;
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        self.color.encode(buf);
+        buf.put_i16_le(0); // reserved
+        buf.put_u16_le(self.power);
+        self.label.encode(buf);
+        buf.put_u64_le(0); // reserved
+    }
+
+    fn decode<B: Buf>(buf: &mut B) -> Result<State, ProtocolError> {
+        check_size::<State, _>(buf)?;
+        let color = Hsbk::decode(buf)?;
+        let _ = buf.get_i16_le(); // reserved
+        let power = buf.get_u16_le();
+        // Devices don't validate labels, so one bad label shouldn't make the rest of the state unreadable
+        let label = Label::decode_lossy(buf);
+        let _ = buf.get_u64_le(); // reserved
+        Ok(State { color, power, label })
+    }
+}
+
 /// Service exposed by a LIFX device
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Service {
     Udp,
     Unknown(u8),
 }
 
-impl Message {
-    pub fn message_type(&self) -> MessageType {
-        match self {
-            Message::GetService => MessageType::GetService,
-            Message::StateService(_) => MessageType::StateService,
-            Message::GetLabel => MessageType::GetLabel,
-            Message::SetLabel(_) => MessageType::SetLabel,
-            Message::StateLabel(_) => MessageType::StateLabel,
-            Message::Acknowledgement => MessageType::Acknowledgement,
-            Message::Get => MessageType::Get,
-            Message::SetColor(_) => MessageType::SetColor,
-            Message::State(_) => MessageType::State,
-
+impl From<Service> for u8 {
+    fn from(service: Service) -> u8 {
+        match service {
+            Service::Udp => 1,
+            Service::Unknown(id) => id,
         }
     }
+}
 
-    pub fn payload_size(&self) -> usize {
-        match self {
-            Message::GetService => 0,
-            Message::StateService(_) => 5,
-            Message::GetLabel => 0,
-            Message::SetLabel(_) => Label::MAX_LENGTH,
-            Message::StateLabel(_) => Label::MAX_LENGTH,
-            Message::Acknowledgement => 0,
-            Message::Get => 0,
-            Message::SetColor(_) => 1 /* reserved */ + Hsbk::SIZE + 4 /* duration */,
-            Message::State(_) =>  Hsbk::SIZE + 2 /* reserved */ + 2 /* power */ + Label::MAX_LENGTH + 8 /* reserved */,
+impl From<u8> for Service {
+    fn from(value: u8) -> Service {
+        match value {
+            1 => Service::Udp,
+            _ => Service::Unknown(value),
         }
     }
+}
 
-    pub(crate) fn encode_payload<B: BufMut>(&self, buf: &mut B) {
-        match self {
-            Message::GetService => (),
-            Message::StateService(service) => {
-                buf.put_u8(service.service.into());
-                buf.put_u32_le(service.port);
-            },
-            Message::GetLabel => (),
-            Message::SetLabel(inner) => {
-                inner.label.encode(buf);
-            },
-            Message::StateLabel(inner) => {
-                inner.label.encode(buf);
-            },
-            Message::Acknowledgement => (),
-            Message::Get => (),
-            Message::SetColor(inner) => {
-                buf.put_u8(0); // reserved
-                inner.color.encode(buf);
-                buf.put_u32_le(inner.duration.as_millis() as u32);
-            },
-            Message::State(inner) => {
-                inner.color.encode(buf);
-                buf.put_i16_le(0); // reserved
-                buf.put_u16_le(inner.power);
-                inner.label.encode(buf);
-                buf.put_u64_le(0); // reserved
-            }
-        }
-    }
+#[test]
+fn test_invalid_label() {
+    // A device with a label that isn't valid UTF-8 still reports the rest of its state
+    let mut encoded = vec![0u8; State::SIZE];
+    encoded[..2].copy_from_slice(&1000u16.to_le_bytes());
+    encoded[12..15].copy_from_slice(b"a\xffb");
 
-    pub(crate) fn decode<B: Buf>(header: &Header, buf: &mut B) -> Result<Message, ProtocolError> {
-        match header.message_type {
-            MessageType::GetService => Ok(Message::GetService),
-            MessageType::StateService => {
-                let service = Service::from(buf.get_u8());
-                let port = buf.get_u32_le();
-                Ok(Message::StateService(StateService { service, port }))
-            },
-            MessageType::GetLabel => Ok(Message::GetLabel),
-            MessageType::SetLabel => {
-                let label = Label::decode(buf)?;
-                Ok(Message::SetLabel(SetLabel { label }))
-            }
-            MessageType::StateLabel => {
-                let label = Label::decode(buf)?;
-                Ok(Message::StateLabel(StateLabel { label }))
-            },
-            MessageType::Acknowledgement => Ok(Message::Acknowledgement),
-            MessageType::Get => Ok(Message::Get),
-            MessageType::SetColor => {
-                let _ = buf.get_u8(); // reserved
-                let color = Hsbk::decode(buf)?;
-                let duration = Duration::from_millis(buf.get_u32_le().into());
-                Ok(Message::SetColor(SetColor { color, duration }))
-            }
-            MessageType::State => {
-                let color = Hsbk::decode(buf)?;
-                let _ = buf.get_i16_le(); // reserved
-                let power = buf.get_u16_le();
-                let label = Label::decode(buf)?;
-                let _ = buf.get_u64_le(); // reserved
-                Ok(Message::State(State { color, power, label }))
-            }
-            MessageType::Other(_) => Err(ProtocolError::UnexpectedMessage(header.message_type))
-        }
-    }
-}
+    let state = State::decode(&mut encoded.as_slice()).unwrap();
+    assert_eq!(state.color.hue, 1000);
+    assert_eq!(state.label.to_string_lossy(), "a\u{FFFD}b");
 
-impl From<u16> for MessageType {
-    fn from(value: u16) -> MessageType {
-        match value {
-            2 => MessageType::GetService,
-            3 => MessageType::StateService,
-            23 => MessageType::GetLabel,
-            24 => MessageType::SetLabel,
-            25 => MessageType::StateLabel,
-            45 => MessageType::Acknowledgement,
-            101 => MessageType::Get,
-            102 => MessageType::SetColor,
-            107 => MessageType::State,
-            _ => MessageType::Other(value),
-        }
-    }
+    let mut reencoded = Vec::new();
+    state.encode(&mut reencoded);
+    assert_eq!(reencoded, encoded);
 }
 
-impl Into<u16> for MessageType {
-    fn into(self) -> u16 {
-        match self {
-            MessageType::GetService => 2,
-            MessageType::StateService => 3,
-            MessageType::GetLabel => 23,
-            MessageType::SetLabel => 24,
-            MessageType::StateLabel => 25,
-            MessageType::Acknowledgement => 45,
-            MessageType::Get => 101,
-            MessageType::SetColor => 102,
-            MessageType::State => 107,
-            MessageType::Other(value) => value,
-        }
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_payload_size(message: Message) {
+        let mut encoded = Vec::new();
+        message.encode_payload(&mut encoded);
+        proptest::prop_assert_eq!(encoded.len(), message.payload_size());
     }
-}
 
-impl Into<u8> for Service {
-    fn into(self) -> u8 {
-        match self {
-            Service::Udp => 1,
-            Service::Unknown(id) => id,
-        }
+    #[test]
+    fn test_message_roundtrip(message: Message, header: Header) {
+        let header = Header {
+            size: (Header::HEADER_SIZE + message.payload_size()) as u16,
+            message_type: message.message_type(),
+            ..header
+        };
+        let mut encoded = Vec::new();
+        message.encode_payload(&mut encoded);
+
+        let mut buf = encoded.as_slice();
+        let decoded = Message::decode(&header, &mut buf).unwrap();
+        proptest::prop_assert_eq!(decoded, message);
+        proptest::prop_assert!(buf.is_empty(), "{} bytes left over after decoding", buf.len());
     }
-}
 
-impl From<u8> for Service {
-    fn from(value: u8) -> Service {
-        match value {
-            1 => Service::Udp,
-            _ => Service::Unknown(value),
+    #[test]
+    fn test_truncated_payload(message: Message, header: Header, len: proptest::sample::Index) {
+        let header = Header { message_type: message.message_type(), ..header };
+        let mut encoded = Vec::new();
+        message.encode_payload(&mut encoded);
+        if encoded.is_empty() {
+            // Messages without a payload can't be truncated
+            return Ok(());
         }
+        encoded.truncate(len.index(encoded.len()));
+
+        let result = Message::decode(&header, &mut encoded.as_slice());
+        let is_too_short = matches!(result, Err(ProtocolError::TooShort { expected, actual }) if expected == message.payload_size() && actual == encoded.len());
+        proptest::prop_assert!(is_too_short, "{:?}", result);
     }
 }
@@ -0,0 +1,241 @@
+//! Capabilities of LIFX products, identified by the vendor and product ids devices report in [`StateVersion`](crate::message::StateVersion) messages.
+//!
+//! The product table is generated from a copy of the [LIFX products registry](https://github.com/LIFX/products).
+
+use core::fmt;
+
+use crate::color::{Hsbk, Kelvin, KelvinError};
+
+/// A LIFX product
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Product {
+    vendor: u32,
+    product: u32,
+    name: &'static str,
+    features: Features,
+    /// Features as of each firmware upgrade that changes them, oldest first
+    upgrades: &'static [(FirmwareVersion, Features)],
+}
+
+/// What a product can do
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features {
+    /// Whether or not the product supports color, as opposed to only white light
+    pub color: bool,
+    /// Whether or not the product has infrared LEDs
+    pub infrared: bool,
+    /// Whether or not the product has a strip of individually addressable zones
+    pub multizone: bool,
+    /// Whether or not the product supports the extended multizone messages, which set every zone at once
+    pub extended_multizone: bool,
+    /// Whether or not several devices can be chained together
+    pub chain: bool,
+    /// Whether or not the product has a two-dimensional grid of zones
+    pub matrix: bool,
+    /// Whether or not the product has HEV (anti-bacterial) LEDs
+    pub hev: bool,
+    /// Whether or not the product has relays, like the LIFX Switch
+    pub relays: bool,
+    /// Whether or not the product has physical buttons
+    pub buttons: bool,
+    /// Supported color temperatures, or `None` if the product doesn't emit light
+    pub temperature_range: Option<TemperatureRange>,
+}
+
+/// An inclusive range of color temperatures. The range is empty if `min` is greater than `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TemperatureRange {
+    pub min: Kelvin,
+    pub max: Kelvin,
+}
+
+/// What to do with a color temperature that a product doesn't support
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KelvinPolicy {
+    /// Use the closest supported temperature
+    Clamp,
+    /// Fail with a [`KelvinError`]
+    Reject,
+}
+
+/// Version of the firmware running on a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirmwareVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+include!(concat!(env!("OUT_DIR"), "/products.rs"));
+
+impl Product {
+    /// Look up a product by its vendor and product ids
+    pub fn lookup(vendor: u32, product: u32) -> Option<&'static Product> {
+        PRODUCTS
+            .binary_search_by_key(&(vendor, product), |p| (p.vendor, p.product))
+            .ok()
+            .map(|idx| &PRODUCTS[idx])
+    }
+
+    /// Every known product
+    pub fn all() -> &'static [Product] {
+        PRODUCTS
+    }
+
+    pub fn vendor(&self) -> u32 {
+        self.vendor
+    }
+
+    pub fn product(&self) -> u32 {
+        self.product
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Features of the product as originally released. Newer firmware may add more, see [`Product::features_with_firmware`].
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
+    /// Features of the product when running firmware `version`
+    pub fn features_with_firmware(&self, version: FirmwareVersion) -> Features {
+        self.upgrades
+            .iter()
+            .rev()
+            .find(|(upgrade, _)| *upgrade <= version)
+            .map_or(self.features, |(_, features)| *features)
+    }
+}
+
+impl fmt::Display for Product {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+impl Features {
+    /// Check that the product can display `color`. If its temperature is unsupported, it's either clamped to the supported range or rejected,
+    /// depending on `policy`. Products that don't emit light, like the LIFX Switch, reject every color.
+    pub fn check_color(&self, color: Hsbk, policy: KelvinPolicy) -> Result<Hsbk, KelvinError> {
+        let unsupported = KelvinError { temperature: color.temperature, range: self.temperature_range };
+        let range = self.temperature_range.filter(|range| !range.is_empty()).ok_or(unsupported)?;
+        let temperature = match policy {
+            KelvinPolicy::Clamp => range.clamp(color.temperature),
+            KelvinPolicy::Reject => range.check(color.temperature)?,
+        };
+        Ok(Hsbk { temperature, ..color })
+    }
+}
+
+impl TemperatureRange {
+    pub const fn new(min: Kelvin, max: Kelvin) -> TemperatureRange {
+        TemperatureRange { min, max }
+    }
+
+    /// Whether or not the range contains no temperatures, because `min` is greater than `max`
+    pub fn is_empty(&self) -> bool {
+        self.min > self.max
+    }
+
+    /// Whether or not `kelvin` is in this range
+    pub fn contains(&self, kelvin: Kelvin) -> bool {
+        (self.min..=self.max).contains(&kelvin)
+    }
+
+    /// The closest temperature to `kelvin` in this range. An empty range has no closest temperature, so this returns `max`.
+    pub fn clamp(&self, kelvin: Kelvin) -> Kelvin {
+        kelvin.max(self.min).min(self.max)
+    }
+
+    /// Return `kelvin` if it's in this range, or an error if it isn't
+    pub fn check(&self, kelvin: Kelvin) -> Result<Kelvin, KelvinError> {
+        if self.contains(kelvin) {
+            Ok(kelvin)
+        } else {
+            Err(KelvinError { temperature: kelvin, range: Some(*self) })
+        }
+    }
+}
+
+impl fmt::Display for TemperatureRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.min, self.max)
+    }
+}
+
+impl FirmwareVersion {
+    pub const fn new(major: u16, minor: u16) -> FirmwareVersion {
+        FirmwareVersion { major, minor }
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+#[test]
+fn test_lookup() {
+    let bulb = Product::lookup(1, 27).unwrap();
+    assert_eq!(bulb.name(), "LIFX A19");
+    assert!(bulb.features().color);
+    assert!(!bulb.features().infrared);
+    assert_eq!(bulb.features().temperature_range, Some(TemperatureRange::new(Kelvin::new(2500), Kelvin::new(9000))));
+
+    let switch = Product::lookup(1, 70).unwrap();
+    assert!(switch.features().relays && switch.features().buttons);
+    assert!(!switch.features().color);
+    assert_eq!(switch.features().temperature_range, None);
+
+    assert!(Product::lookup(1, 2).is_none());
+    assert!(Product::lookup(2, 27).is_none());
+    assert!(Product::all().windows(2).all(|pair| (pair[0].vendor, pair[0].product) < (pair[1].vendor, pair[1].product)));
+}
+
+#[test]
+fn test_firmware_upgrades() {
+    // The LIFX Z gained extended multizone support in 2.77, and a wider temperature range in 2.80
+    let strip = Product::lookup(1, 32).unwrap();
+    assert!(strip.features().multizone);
+    assert!(!strip.features().extended_multizone);
+
+    let old = strip.features_with_firmware(FirmwareVersion::new(2, 76));
+    assert_eq!(old, strip.features());
+
+    let extended = strip.features_with_firmware(FirmwareVersion::new(2, 77));
+    assert!(extended.extended_multizone);
+    assert_eq!(extended.temperature_range, Some(TemperatureRange::new(Kelvin::new(2500), Kelvin::new(9000))));
+
+    let latest = strip.features_with_firmware(FirmwareVersion::new(3, 0));
+    assert!(latest.extended_multizone);
+    assert_eq!(latest.temperature_range, Some(TemperatureRange::new(Kelvin::new(1500), Kelvin::new(9000))));
+}
+
+#[test]
+fn test_check_color() {
+    let bulb = Product::lookup(1, 27).unwrap().features();
+    let warm = Hsbk { hue: 0, saturation: 0, brightness: 65535, temperature: Kelvin::new(1500) };
+
+    // Older firmware only goes down to 2500K
+    assert_eq!(bulb.check_color(warm, KelvinPolicy::Clamp).unwrap().temperature, Kelvin::new(2500));
+    let err = bulb.check_color(warm, KelvinPolicy::Reject).unwrap_err();
+    assert_eq!(err.temperature(), Kelvin::new(1500));
+    assert_eq!(err.to_string(), "1500K is outside the supported range of 2500K-9000K");
+
+    let upgraded = Product::lookup(1, 27).unwrap().features_with_firmware(FirmwareVersion::new(2, 80));
+    assert_eq!(upgraded.check_color(warm, KelvinPolicy::Reject).unwrap(), warm);
+
+    let switch = Product::lookup(1, 70).unwrap().features();
+    assert!(switch.check_color(warm, KelvinPolicy::Clamp).is_err());
+
+    // An empty range rejects every temperature rather than panicking
+    let empty = TemperatureRange::new(Kelvin::new(9000), Kelvin::new(2500));
+    assert!(empty.is_empty());
+    assert_eq!(empty.clamp(warm.temperature), Kelvin::new(2500));
+    let features = Features { temperature_range: Some(empty), ..bulb };
+    assert!(features.check_color(warm, KelvinPolicy::Clamp).is_err());
+    assert!(features.check_color(warm, KelvinPolicy::Reject).is_err());
+}
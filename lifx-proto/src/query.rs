@@ -0,0 +1,54 @@
+//! Links between request messages and the replies devices send to them
+
+use crate::message::{
+    Get, GetHostFirmware, GetLabel, GetService, GetVersion, Message, SetColor, SetLabel, State, StateHostFirmware, StateLabel, StateService,
+    StateVersion,
+};
+use crate::ProtocolError;
+
+/// A request message that devices answer with a reply of type [`Query::Reply`].
+///
+/// Devices always reply to `Get*` messages. They only reply to `Set*` messages if a response is required, in which case they reply with the new state.
+pub trait Query: Into<Message> {
+    /// Payload of the reply message
+    type Reply;
+
+    /// Extract the reply payload from `message`, failing if it isn't the expected reply type
+    fn reply(message: Message) -> Result<Self::Reply, ProtocolError>;
+}
+
+macro_rules! query {
+    ($request:ty => $reply:ident) => {
+        impl Query for $request {
+            type Reply = $reply;
+
+            fn reply(message: Message) -> Result<$reply, ProtocolError> {
+                match message {
+                    Message::$reply(inner) => Ok(inner),
+                    other => Err(ProtocolError::UnexpectedMessage(other.message_type())),
+                }
+            }
+        }
+    };
+}
+
+query!(GetService => StateService);
+query!(GetHostFirmware => StateHostFirmware);
+query!(GetLabel => StateLabel);
+query!(GetVersion => StateVersion);
+query!(SetLabel => StateLabel);
+query!(Get => State);
+query!(SetColor => State);
+
+#[test]
+fn test_reply() {
+    use crate::label::Label;
+    use crate::message::MessageType;
+
+    let label = StateLabel { label: Label::new("Kitchen") };
+    assert_eq!(GetLabel::reply(Message::StateLabel(label.clone())).unwrap(), label);
+    assert!(matches!(
+        Get::reply(Message::StateLabel(label)),
+        Err(ProtocolError::UnexpectedMessage(MessageType::StateLabel))
+    ));
+}
@@ -0,0 +1,266 @@
+//! Conversions between LIFX colors and RGB, without depending on `palette`.
+//!
+//! LIFX devices treat hue, saturation, and brightness as HSV coordinates over sRGB, so converting to and from 8-bit sRGB is exact up to rounding.
+//! Converting a color temperature to RGB uses [Tanner Helland's approximation](https://tannerhelland.com/2012/09/18/convert-temperature-rgb-algorithm-code.html)
+//! of the blackbody curve, which needs the `std` feature for its logarithms and powers.
+
+use alloc::string::{String, ToString};
+use core::fmt;
+use core::str::FromStr;
+
+use crate::color::{Hsbk, Kelvin};
+use crate::math::{scale_unit, unit};
+
+/// An 8-bit sRGB color
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rgb {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Rgb {
+    pub const fn new(red: u8, green: u8, blue: u8) -> Rgb {
+        Rgb { red, green, blue }
+    }
+
+    /// Parse a `#rrggbb` hex string. The leading `#` is optional, and digits may be upper- or lowercase.
+    pub fn from_hex(hex: &str) -> Result<Rgb, RgbParseError> {
+        let digits = hex.strip_prefix('#').unwrap_or(hex);
+        if digits.len() != 6 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(RgbParseError(()));
+        }
+
+        let component = |idx: usize| u8::from_str_radix(&digits[idx..idx + 2], 16).map_err(|_| RgbParseError(()));
+        Ok(Rgb::new(component(0)?, component(2)?, component(4)?))
+    }
+
+    fn from_unit(red: f32, green: f32, blue: f32) -> Rgb {
+        Rgb::new(to_u8(red), to_u8(green), to_u8(blue))
+    }
+}
+
+impl FromStr for Rgb {
+    type Err = RgbParseError;
+
+    fn from_str(s: &str) -> Result<Rgb, RgbParseError> {
+        Rgb::from_hex(s)
+    }
+}
+
+/// Formats as a lowercase `#rrggbb` hex string
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)
+    }
+}
+
+/// A string that isn't a `#rrggbb` hex color
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RgbParseError(());
+
+impl fmt::Display for RgbParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("invalid hex color, expected #rrggbb")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RgbParseError {}
+
+impl Hsbk {
+    /// Convert an sRGB color. RGB colors don't have a color temperature, so `temperature` is used for it. It only affects how the color is displayed
+    /// if it's unsaturated.
+    pub fn from_rgb(rgb: Rgb, temperature: Kelvin) -> Hsbk {
+        let (red, green, blue) = (u32::from(rgb.red), u32::from(rgb.green), u32::from(rgb.blue));
+        let max = red.max(green).max(blue);
+        let min = red.min(green).min(blue);
+        let chroma = max - min;
+
+        let hue_degrees = if chroma == 0 {
+            0.0
+        } else {
+            let chroma = chroma as f32;
+            let sector = if max == red {
+                (green as f32 - blue as f32) / chroma
+            } else if max == green {
+                (blue as f32 - red as f32) / chroma + 2.0
+            } else {
+                (red as f32 - green as f32) / chroma + 4.0
+            };
+            let degrees = sector * 60.0;
+            if degrees < 0.0 {
+                degrees + 360.0
+            } else {
+                degrees
+            }
+        };
+
+        Hsbk {
+            hue: scale_unit(hue_degrees / 360.0),
+            // Black has no saturation
+            saturation: (chroma * 65535 + max / 2).checked_div(max).unwrap_or(0) as u16,
+            // 65535 / 255 = 257, so this is exact
+            brightness: (max * 257) as u16,
+            temperature,
+        }
+    }
+
+    /// Parse a `#rrggbb` hex color, using `temperature` as its color temperature. See [`Hsbk::from_rgb`].
+    pub fn from_hex(hex: &str, temperature: Kelvin) -> Result<Hsbk, RgbParseError> {
+        Rgb::from_hex(hex).map(|rgb| Hsbk::from_rgb(rgb, temperature))
+    }
+
+    /// Convert the hue, saturation, and brightness to sRGB, ignoring the color temperature. See [`Hsbk::rendered`] for a conversion that includes it.
+    pub fn to_rgb(&self) -> Rgb {
+        let (red, green, blue) = hue_to_unit_rgb(self.hue);
+        let saturation = unit(self.saturation);
+        let brightness = unit(self.brightness);
+        // Desaturating mixes in white
+        let mix = |component: f32| brightness * (1.0 - saturation + saturation * component);
+        Rgb::from_unit(mix(red), mix(green), mix(blue))
+    }
+
+    /// Format the color as a `#rrggbb` hex string, ignoring the color temperature
+    pub fn to_hex(&self) -> String {
+        self.to_rgb().to_string()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Hsbk {
+    /// Approximately how the color looks on a device. Unlike [`Hsbk::to_rgb`], unsaturated colors are tinted by the color temperature, since
+    /// devices mix their white LEDs in as saturation decreases.
+    pub fn rendered(&self) -> Rgb {
+        let white = self.temperature.to_rgb();
+        let (red, green, blue) = hue_to_unit_rgb(self.hue);
+        let saturation = unit(self.saturation);
+        let brightness = unit(self.brightness);
+        let mix = |white: u8, component: f32| brightness * ((1.0 - saturation) * (f32::from(white) / 255.0) + saturation * component);
+        Rgb::from_unit(mix(white.red, red), mix(white.green, green), mix(white.blue, blue))
+    }
+}
+
+#[cfg(feature = "std")]
+impl Kelvin {
+    /// Approximate sRGB color of a blackbody at this temperature, at full brightness. This is the tint of white light from a device set to this
+    /// temperature. The approximation is intended for temperatures from 1000K to 40000K.
+    pub fn to_rgb(self) -> Rgb {
+        let temperature = f32::from(u16::from(self)) / 100.0;
+
+        let red = if temperature <= 66.0 {
+            255.0
+        } else {
+            329.698_73 * (temperature - 60.0).powf(-0.133_204_76)
+        };
+        let green = if temperature <= 66.0 {
+            99.470_8 * temperature.ln() - 161.119_57
+        } else {
+            288.122_17 * (temperature - 60.0).powf(-0.075_514_846)
+        };
+        let blue = if temperature >= 66.0 {
+            255.0
+        } else if temperature <= 19.0 {
+            0.0
+        } else {
+            138.517_73 * (temperature - 10.0).ln() - 305.044_8
+        };
+
+        Rgb::from_unit(red / 255.0, green / 255.0, blue / 255.0)
+    }
+}
+
+/// The fully saturated, full brightness color with LIFX hue `hue`, with components from 0 to 1
+fn hue_to_unit_rgb(hue: u16) -> (f32, f32, f32) {
+    let sector = unit(hue) * 6.0;
+    // Truncation is the same as flooring, since the sector is never negative
+    let idx = sector as u32;
+    let fraction = sector - idx as f32;
+    match idx % 6 {
+        0 => (1.0, fraction, 0.0),
+        1 => (1.0 - fraction, 1.0, 0.0),
+        2 => (0.0, 1.0, fraction),
+        3 => (0.0, 1.0 - fraction, 1.0),
+        4 => (fraction, 0.0, 1.0),
+        _ => (1.0, 0.0, 1.0 - fraction),
+    }
+}
+
+/// Scale a value from 0 to 1 to an 8-bit component, rounding to the nearest value. Out-of-range values are clamped.
+fn to_u8(value: f32) -> u8 {
+    // Float to int casts saturate, so this only needs to handle rounding
+    (value * 255.0 + 0.5) as u8
+}
+
+#[test]
+fn test_rgb_reference_values() {
+    let kelvin = Kelvin::new(3500);
+    let cases = [
+        ("#ff0000", 0, 65535, 65535),
+        ("#00ff00", 21845, 65535, 65535),
+        ("#0000ff", 43690, 65535, 65535),
+        ("#00ffff", 32768, 65535, 65535),
+        ("#ff00ff", 54613, 65535, 65535),
+        ("#ffffff", 0, 0, 65535),
+        ("#808080", 0, 0, 32896),
+        ("#000000", 0, 0, 0),
+        ("#800000", 0, 65535, 32896),
+    ];
+    for (hex, hue, saturation, brightness) in cases.iter().copied() {
+        let color = Hsbk::from_hex(hex, kelvin).unwrap();
+        assert_eq!(color, Hsbk { hue, saturation, brightness, temperature: kelvin }, "converting {}", hex);
+        assert_eq!(color.to_hex(), hex);
+    }
+
+    assert_eq!(Rgb::from_hex("FFA500"), Ok(Rgb::new(255, 165, 0)));
+    assert!(Rgb::from_hex("#ffa50").is_err());
+    assert!(Rgb::from_hex("#ffa5000").is_err());
+    assert!(Rgb::from_hex("#+fa500").is_err());
+    assert!(Rgb::from_hex("#ffa5g0").is_err());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_blackbody_reference_values() {
+    let cases = [
+        (1000, Rgb::new(255, 68, 0)),
+        (1500, Rgb::new(255, 108, 0)),
+        (2700, Rgb::new(255, 167, 87)),
+        (3500, Rgb::new(255, 193, 141)),
+        (5000, Rgb::new(255, 228, 206)),
+        (6500, Rgb::new(255, 254, 250)),
+        (6600, Rgb::new(255, 255, 255)),
+        (9000, Rgb::new(210, 223, 255)),
+    ];
+    for (kelvin, rgb) in cases.iter().copied() {
+        assert_eq!(Kelvin::new(kelvin).to_rgb(), rgb, "converting {}K", kelvin);
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_rendered() {
+    // Fully saturated colors aren't affected by temperature...
+    let red = Hsbk { hue: 0, saturation: 65535, brightness: 65535, temperature: Kelvin::new(2700) };
+    assert_eq!(red.rendered(), Rgb::new(255, 0, 0));
+
+    // ...but white is
+    let warm = Hsbk { hue: 0, saturation: 0, brightness: 65535, temperature: Kelvin::new(2700) };
+    assert_eq!(warm.rendered(), Rgb::new(255, 167, 87));
+    assert_eq!(warm.to_rgb(), Rgb::new(255, 255, 255));
+
+    let dim_warm = Hsbk { brightness: 32768, ..warm };
+    assert_eq!(dim_warm.rendered(), Rgb::new(128, 84, 44));
+
+    let pastel = Hsbk { hue: 43690, saturation: 32768, brightness: 65535, temperature: Kelvin::new(6600) };
+    assert_eq!(pastel.rendered(), Rgb::new(127, 127, 255));
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_rgb_roundtrip(red: u8, green: u8, blue: u8) {
+        let rgb = Rgb::new(red, green, blue);
+        proptest::prop_assert_eq!(Hsbk::from_rgb(rgb, Kelvin::new(3500)).to_rgb(), rgb);
+    }
+}
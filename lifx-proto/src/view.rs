@@ -0,0 +1,253 @@
+//! Borrowed views of encoded packets, for decoding without copying or allocating.
+//!
+//! [`PacketRef::parse`] only decodes the header and checks that the payload is long enough for its message type. Payload fields are read from the
+//! underlying buffer when they're accessed, and labels are only validated as UTF-8 when they're converted to strings.
+
+use core::fmt;
+use core::marker::PhantomData;
+use core::str;
+
+use bytes::Buf;
+
+use crate::color::Hsbk;
+use crate::duration::ProtocolDuration;
+use crate::header::{DeviceTarget, Header};
+use crate::label::Label;
+use crate::message::{MessageRef, Payload, Service, SetColor, SetLabel, State, StateLabel, StateService};
+use crate::{Packet, ProtocolError};
+
+/// A [`Packet`] borrowed from an encoded buffer
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PacketRef<'a> {
+    header: Header,
+    message: MessageRef<'a>,
+}
+
+impl<'a> PacketRef<'a> {
+    /// Parse a packet from `bytes`, which must hold at least the size given in the packet header. Any bytes after that are ignored.
+    pub fn parse(bytes: &'a [u8]) -> Result<PacketRef<'a>, ProtocolError> {
+        check_len(bytes, Header::HEADER_SIZE)?;
+        let header = Header::decode(&mut &bytes[..Header::HEADER_SIZE])?;
+
+        let size = header.size as usize;
+        if size < Header::HEADER_SIZE {
+            return Err(ProtocolError::TooShort { expected: Header::HEADER_SIZE, actual: size });
+        }
+        check_len(bytes, size)?;
+
+        let message = MessageRef::new(header.message_type, &bytes[Header::HEADER_SIZE..size])?;
+        Ok(PacketRef { header, message })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn source(&self) -> u32 {
+        self.header.source
+    }
+
+    pub fn sequence(&self) -> u8 {
+        self.header.sequence
+    }
+
+    pub fn target(&self) -> DeviceTarget {
+        self.header.target
+    }
+
+    pub fn response_required(&self) -> bool {
+        self.header.response_required
+    }
+
+    pub fn acknowledgement_required(&self) -> bool {
+        self.header.acknowledgement_required
+    }
+
+    pub fn message(&self) -> MessageRef<'a> {
+        self.message
+    }
+
+    /// Decode the full, owned [`Packet`]
+    pub fn to_packet(&self) -> Result<Packet, ProtocolError> {
+        Ok(Packet::new(
+            self.header.source,
+            self.header.target,
+            self.header.sequence,
+            self.header.response_required,
+            self.header.acknowledgement_required,
+            self.message.to_message()?,
+        ))
+    }
+}
+
+/// Payload of type `P`, borrowed from an encoded packet
+pub struct PayloadRef<'a, P> {
+    bytes: &'a [u8],
+    payload: PhantomData<P>,
+}
+
+impl<'a, P: Payload> PayloadRef<'a, P> {
+    /// Borrow a payload from `bytes`, which must be at least [`Payload::SIZE`] bytes long
+    pub fn new(bytes: &'a [u8]) -> Result<PayloadRef<'a, P>, ProtocolError> {
+        check_len(bytes, P::SIZE)?;
+        Ok(PayloadRef { bytes: &bytes[..P::SIZE], payload: PhantomData })
+    }
+
+    /// The encoded payload
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Decode the full, owned payload
+    pub fn decode(&self) -> Result<P, ProtocolError> {
+        P::decode(&mut &self.bytes[..])
+    }
+
+    /// Field data starting `offset` bytes into the payload
+    fn at(&self, offset: usize) -> &'a [u8] {
+        &self.bytes[offset..]
+    }
+}
+
+impl<'a, P> Clone for PayloadRef<'a, P> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, P> Copy for PayloadRef<'a, P> {}
+
+impl<'a, P> PartialEq for PayloadRef<'a, P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl<'a, P> Eq for PayloadRef<'a, P> {}
+
+impl<'a, P> fmt::Debug for PayloadRef<'a, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("PayloadRef").field(&self.bytes).finish()
+    }
+}
+
+impl<'a> PayloadRef<'a, StateService> {
+    pub fn service(&self) -> Service {
+        Service::from(self.at(0).get_u8())
+    }
+
+    pub fn port(&self) -> u32 {
+        self.at(1).get_u32_le()
+    }
+}
+
+impl<'a> PayloadRef<'a, SetLabel> {
+    pub fn label(&self) -> LabelRef<'a> {
+        LabelRef::new(self.at(0))
+    }
+}
+
+impl<'a> PayloadRef<'a, StateLabel> {
+    pub fn label(&self) -> LabelRef<'a> {
+        LabelRef::new(self.at(0))
+    }
+}
+
+impl<'a> PayloadRef<'a, SetColor> {
+    pub fn color(&self) -> Result<Hsbk, ProtocolError> {
+        Hsbk::decode(&mut self.at(1))
+    }
+
+    /// Color transition time
+    pub fn duration(&self) -> ProtocolDuration {
+        ProtocolDuration::decode(&mut self.at(1 + Hsbk::SIZE))
+    }
+}
+
+impl<'a> PayloadRef<'a, State> {
+    pub fn color(&self) -> Result<Hsbk, ProtocolError> {
+        Hsbk::decode(&mut self.at(0))
+    }
+
+    pub fn power(&self) -> u16 {
+        self.at(Hsbk::SIZE + 2).get_u16_le()
+    }
+
+    pub fn label(&self) -> LabelRef<'a> {
+        LabelRef::new(self.at(Hsbk::SIZE + 4))
+    }
+}
+
+/// A [`Label`] borrowed from an encoded packet. It isn't checked to be valid UTF-8 until it's converted to a string.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LabelRef<'a>(&'a [u8]);
+
+impl<'a> LabelRef<'a> {
+    fn new(bytes: &'a [u8]) -> LabelRef<'a> {
+        LabelRef(&bytes[..Label::MAX_LENGTH])
+    }
+
+    /// The encoded label, including any NUL padding
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    pub fn to_str(&self) -> Result<&'a str, ProtocolError> {
+        match str::from_utf8(self.0) {
+            Ok(str) => Ok(str.trim_end_matches(char::from(0))),
+            Err(_) => Err(ProtocolError::InvalidLabel),
+        }
+    }
+
+    pub fn to_label(&self) -> Result<Label, ProtocolError> {
+        Label::decode(&mut &self.0[..])
+    }
+
+    /// Convert to a [`Label`], accepting invalid UTF-8. See [`Label::decode_lossy`].
+    pub fn to_label_lossy(&self) -> Label {
+        Label::decode_lossy(&mut &self.0[..])
+    }
+}
+
+fn check_len(bytes: &[u8], expected: usize) -> Result<(), ProtocolError> {
+    if bytes.len() < expected {
+        Err(ProtocolError::TooShort { expected, actual: bytes.len() })
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_packet_ref(packet: Packet) {
+        let mut encoded = Vec::new();
+        packet.encode(&mut encoded).unwrap();
+
+        let view = PacketRef::parse(&encoded).unwrap();
+        proptest::prop_assert_eq!(view.message().message_type(), packet.message().message_type());
+        proptest::prop_assert_eq!(view.to_packet().unwrap(), packet.clone());
+
+        // Every prefix is too short to hold the whole packet
+        for len in 0..encoded.len() {
+            let is_too_short = matches!(PacketRef::parse(&encoded[..len]), Err(ProtocolError::TooShort { .. }));
+            proptest::prop_assert!(is_too_short);
+        }
+    }
+
+    #[test]
+    fn test_payload_fields(state: State, set_color: SetColor) {
+        let mut encoded = Vec::new();
+        state.encode(&mut encoded);
+        let view = PayloadRef::<State>::new(&encoded).unwrap();
+        proptest::prop_assert_eq!(view.color().unwrap(), state.color);
+        proptest::prop_assert_eq!(view.power(), state.power);
+        proptest::prop_assert_eq!(view.label().to_str().unwrap(), state.label.as_str());
+
+        let mut encoded = Vec::new();
+        set_color.encode(&mut encoded);
+        let view = PayloadRef::<SetColor>::new(&encoded).unwrap();
+        proptest::prop_assert_eq!(view.color().unwrap(), set_color.color);
+        proptest::prop_assert_eq!(view.duration(), set_color.duration);
+    }
+}
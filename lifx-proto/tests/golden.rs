@@ -0,0 +1,207 @@
+//! Known-good packets, checked byte-for-byte in both directions.
+//!
+//! Every vector is either a published example or written out by hand from the field tables in the LIFX documentation, never output of this
+//! crate's encoder, and records where it came from. Checking the encoder against itself would miss exactly the byte-order mistakes these tests
+//! are for. The hand-written vectors use a distinct value in every field they can, so a field read from the wrong offset doesn't go unnoticed. [`test_header_layout`] additionally checks each header
+//! field against the offsets in the [header documentation](https://lan.developer.lifx.com/docs/header-description). Every multi-byte field is
+//! little-endian.
+
+use lifx_proto::color::{Hsbk, Kelvin};
+use lifx_proto::duration::ProtocolDuration;
+use lifx_proto::label::Label;
+use lifx_proto::message::{SetColor, State, StateLabel, StateService};
+use lifx_proto::{DeviceTarget, Message, Packet, PacketRef, Service};
+
+const HEADER_DOCS: &str = "https://lan.developer.lifx.com/docs/header-description";
+const MESSAGE_DOCS: &str = "https://lan.developer.lifx.com/docs/information-messages";
+
+struct Vector {
+    name: &'static str,
+    /// Where the bytes were published, or the documentation they were written from
+    source: String,
+    hex: &'static str,
+    packet: Packet,
+}
+
+fn vectors() -> Vec<Vector> {
+    let hand_written = |table: &str| format!("written by hand from the header table at {} and the {} table at {}", HEADER_DOCS, table, MESSAGE_DOCS);
+    let target = DeviceTarget::Targeted([0xd0, 0x73, 0xd5, 0x01, 0x02, 0x03].into());
+
+    vec![
+        Vector {
+            name: "SetColor to all devices",
+            source: "worked example at https://lan.developer.lifx.com/docs/building-a-lifx-packet".to_string(),
+            hex: "
+                31 00  00 34  00 00 00 00
+                00 00 00 00 00 00 00 00  00 00 00 00 00 00  00  00
+                00 00 00 00 00 00 00 00  66 00  00 00
+                00  55 55  ff ff  ff ff  ac 0d  00 04 00 00
+            ",
+            packet: Packet::new(
+                0,
+                DeviceTarget::All,
+                0,
+                false,
+                false,
+                Message::SetColor(SetColor {
+                    color: Hsbk { hue: 0x5555, saturation: 0xffff, brightness: 0xffff, temperature: Kelvin::new(3500) },
+                    duration: ProtocolDuration::from_millis(1024),
+                }),
+            ),
+        },
+        Vector {
+            name: "GetService discovery broadcast",
+            source: format!("written by hand from the header table at {}, and the discovery section's note that GetService is sent tagged", HEADER_DOCS),
+            // size 36, tagged | addressable | protocol 1024, source 0x12345678
+            // target all, reserved, response required, sequence 1
+            // reserved, type 2, reserved
+            hex: "
+                24 00  00 34  78 56 34 12
+                00 00 00 00 00 00 00 00  00 00 00 00 00 00  01  01
+                00 00 00 00 00 00 00 00  02 00  00 00
+            ",
+            packet: Packet::new(0x1234_5678, DeviceTarget::All, 1, true, false, Message::GetService),
+        },
+        Vector {
+            name: "StateService reply",
+            source: hand_written("StateService (3)"),
+            // size 41, addressable | protocol 1024, source 0x12345678
+            // target, reserved, no flags, sequence 1
+            // reserved, type 3, reserved
+            // service UDP (1), port 56700 as a u32
+            hex: "
+                29 00  00 14  78 56 34 12
+                d0 73 d5 01 02 03 00 00  00 00 00 00 00 00  00  01
+                00 00 00 00 00 00 00 00  03 00  00 00
+                01  7c dd 00 00
+            ",
+            packet: Packet::new(
+                0x1234_5678,
+                target,
+                1,
+                false,
+                false,
+                Message::StateService(StateService { service: Service::Udp, port: 56700 }),
+            ),
+        },
+        Vector {
+            name: "Acknowledgement",
+            source: hand_written("Acknowledgement (45)"),
+            // size 36, addressable | protocol 1024, source 0x12345678
+            // target, reserved, no flags, sequence 0xfe
+            // reserved, type 45, reserved, and no payload
+            hex: "
+                24 00  00 14  78 56 34 12
+                d0 73 d5 01 02 03 00 00  00 00 00 00 00 00  00  fe
+                00 00 00 00 00 00 00 00  2d 00  00 00
+            ",
+            packet: Packet::new(0x1234_5678, target, 0xfe, false, false, Message::Acknowledgement),
+        },
+        Vector {
+            name: "StateLabel reply",
+            source: hand_written("StateLabel (25)"),
+            // size 68, addressable | protocol 1024, source 0x12345678
+            // target, reserved, no flags, sequence 42
+            // reserved, type 25, reserved
+            // "Kitchen", null-padded to 32 bytes
+            hex: "
+                44 00  00 14  78 56 34 12
+                d0 73 d5 01 02 03 00 00  00 00 00 00 00 00  00  2a
+                00 00 00 00 00 00 00 00  19 00  00 00
+                4b 69 74 63 68 65 6e 00  00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00
+            ",
+            packet: Packet::new(0x1234_5678, target, 42, false, false, Message::StateLabel(StateLabel { label: Label::new("Kitchen") })),
+        },
+        Vector {
+            name: "LightState reply",
+            source: hand_written("LightState (107)"),
+            // size 88, addressable | protocol 1024, source 0x12345678
+            // target, reserved, no flags, sequence 43
+            // reserved, type 107, reserved
+            // hue 0x1234, saturation 0xfedc, brightness 0x8000, kelvin 2500
+            // reserved i16, then power 0xffff
+            // "Kitchen", null-padded to 32 bytes
+            // 8 trailing reserved bytes
+            hex: "
+                58 00  00 14  78 56 34 12
+                d0 73 d5 01 02 03 00 00  00 00 00 00 00 00  00  2b
+                00 00 00 00 00 00 00 00  6b 00  00 00
+                34 12  dc fe  00 80  c4 09
+                00 00  ff ff
+                4b 69 74 63 68 65 6e 00  00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00
+                00 00 00 00 00 00 00 00
+            ",
+            packet: Packet::new(
+                0x1234_5678,
+                target,
+                43,
+                false,
+                false,
+                Message::State(State {
+                    color: Hsbk { hue: 0x1234, saturation: 0xfedc, brightness: 0x8000, temperature: Kelvin::new(2500) },
+                    power: 0xffff,
+                    label: Label::new("Kitchen"),
+                }),
+            ),
+        },
+    ]
+}
+
+fn parse_hex(hex: &str) -> Vec<u8> {
+    hex.split_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16).expect("invalid hex byte"))
+        .collect()
+}
+
+#[test]
+fn test_golden_encode() {
+    for vector in vectors() {
+        let expected = parse_hex(vector.hex);
+        let mut encoded = Vec::new();
+        vector.packet.encode(&mut encoded).unwrap();
+        assert_eq!(encoded, expected, "encoding {} ({})", vector.name, vector.source);
+        assert_eq!(vector.packet.len(), expected.len(), "length of {}", vector.name);
+    }
+}
+
+#[test]
+fn test_golden_decode() {
+    for vector in vectors() {
+        let bytes = parse_hex(vector.hex);
+        let mut buf = bytes.as_slice();
+        let decoded = Packet::decode(&mut buf).unwrap_or_else(|err| panic!("decoding {}: {}", vector.name, err));
+        assert_eq!(decoded, vector.packet, "decoding {} ({})", vector.name, vector.source);
+        assert!(buf.is_empty(), "{} bytes left over after decoding {}", buf.len(), vector.name);
+
+        let view = PacketRef::parse(&bytes).unwrap_or_else(|err| panic!("parsing {}: {}", vector.name, err));
+        assert_eq!(view.to_packet().unwrap(), vector.packet, "parsing {}", vector.name);
+    }
+}
+
+/// Checks the fields the documented example leaves at zero: source, target, flags, and sequence
+#[test]
+fn test_header_layout() {
+    let mac = [0xd0, 0x73, 0xd5, 0x01, 0x02, 0x03];
+    let packet = Packet::builder(Message::GetLabel)
+        .with_source(0x1234_5678)
+        .with_target(DeviceTarget::Targeted(mac.into()))
+        .with_sequence(0xab)
+        .with_response_required(true)
+        .with_acknowledgement_required(true)
+        .build();
+    let mut bytes = Vec::new();
+    packet.encode(&mut bytes).unwrap();
+
+    let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+    assert_eq!(usize::from(u16_at(0)), bytes.len(), "size");
+    // Protocol 1024 and addressable, but not tagged since there's a target
+    assert_eq!(u16_at(2), 0x1400, "protocol, addressable, tagged, and origin");
+    assert_eq!(bytes[4..8], [0x78, 0x56, 0x34, 0x12], "source");
+    assert_eq!(bytes[8..16], [0xd0, 0x73, 0xd5, 0x01, 0x02, 0x03, 0x00, 0x00], "target, in wire order");
+    assert_eq!(bytes[16..22], [0; 6], "reserved");
+    assert_eq!(bytes[22], 0b11, "response and acknowledgement required");
+    assert_eq!(bytes[23], 0xab, "sequence");
+    assert_eq!(bytes[24..32], [0; 8], "reserved");
+    assert_eq!(u16_at(32), 23, "GetLabel message type");
+    assert_eq!(bytes[34..36], [0; 2], "reserved");
+}
@@ -0,0 +1,74 @@
+//! Human-friendly serialized forms of protocol types
+#![cfg(feature = "serde")]
+
+use lifx_proto::color::{BrightnessCurve, Gamma, Hsbk, Kelvin, Lut};
+use lifx_proto::duration::ProtocolDuration;
+use lifx_proto::label::Label;
+use lifx_proto::message::{SetColor, State};
+use lifx_proto::{DeviceTarget, Message};
+use serde_json::json;
+
+#[test]
+fn test_device_target() {
+    let target = DeviceTarget::Targeted([0xd0, 0x73, 0xd5, 0x01, 0x0a, 0xff].into());
+    assert_eq!(serde_json::to_value(target).unwrap(), json!("d0:73:d5:01:0a:ff"));
+    assert_eq!(serde_json::to_value(DeviceTarget::All).unwrap(), json!("all"));
+
+    assert_eq!(serde_json::from_value::<DeviceTarget>(json!("D0:73:D5:01:0A:FF")).unwrap(), target);
+    assert_eq!(serde_json::from_value::<DeviceTarget>(json!("all")).unwrap(), DeviceTarget::All);
+    assert!(serde_json::from_value::<DeviceTarget>(json!("d0:73:d5")).is_err());
+}
+
+#[test]
+fn test_state() {
+    let state = State {
+        color: Hsbk { hue: 1, saturation: 2, brightness: 3, temperature: Kelvin::new(3500) },
+        power: 65535,
+        label: Label::new("Kitchen"),
+    };
+    let expected = json!({
+        "color": { "hue": 1, "saturation": 2, "brightness": 3, "temperature": 3500 },
+        "power": 65535,
+        "label": "Kitchen",
+    });
+    assert_eq!(serde_json::to_value(&state).unwrap(), expected);
+    assert_eq!(serde_json::from_value::<State>(expected).unwrap(), state);
+}
+
+#[test]
+fn test_set_color() {
+    let message = Message::SetColor(SetColor {
+        color: Hsbk { hue: 0, saturation: 0, brightness: 65535, temperature: Kelvin::new(2700) },
+        duration: ProtocolDuration::from_millis(1500),
+    });
+    let expected = json!({
+        "SetColor": {
+            "color": { "hue": 0, "saturation": 0, "brightness": 65535, "temperature": 2700 },
+            "duration": 1500,
+        }
+    });
+    assert_eq!(serde_json::to_value(&message).unwrap(), expected);
+    assert_eq!(serde_json::from_value::<Message>(expected).unwrap(), message);
+}
+
+#[test]
+fn test_validation() {
+    // Labels that couldn't be sent to a device are rejected, but any temperature can be represented
+    assert_eq!(serde_json::from_value::<Kelvin>(json!(100)).unwrap(), Kelvin::new(100));
+    assert!(serde_json::from_value::<Label>(json!("x".repeat(Label::MAX_LENGTH + 1))).is_err());
+    assert!(serde_json::from_value::<Lut>(json!([0, 100, 50])).is_err());
+}
+
+#[test]
+fn test_brightness_curve() {
+    let curve = BrightnessCurve::Lut(Lut::new(vec![0, 16384, 65535]).unwrap());
+    let expected = json!({ "Lut": [0, 16384, 65535] });
+    assert_eq!(serde_json::to_value(&curve).unwrap(), expected);
+    assert_eq!(serde_json::from_value::<BrightnessCurve>(expected).unwrap(), curve);
+    assert_eq!(serde_json::from_value::<BrightnessCurve>(json!("CieLightness")).unwrap(), BrightnessCurve::CieLightness);
+
+    let gamma = BrightnessCurve::Gamma(Gamma::new(2.5).unwrap());
+    assert_eq!(serde_json::to_value(&gamma).unwrap(), json!({ "Gamma": 2.5 }));
+    assert_eq!(serde_json::from_value::<BrightnessCurve>(json!({ "Gamma": 2.5 })).unwrap(), gamma);
+    assert!(serde_json::from_value::<BrightnessCurve>(json!({ "Gamma": -1.0 })).is_err());
+}
@@ -0,0 +1,149 @@
+//! State and message handling for a single simulated device
+
+use lifx_proto::color::{Hsbk, Kelvin};
+use lifx_proto::label::Label;
+use lifx_proto::message::{State, StateHostFirmware, StateLabel, StateService, StateVersion};
+use lifx_proto::products::FirmwareVersion;
+use lifx_proto::{DeviceTarget, Message, Packet, Service};
+use macaddr::MacAddr6;
+
+/// A simulated LIFX light
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualDevice {
+    mac: MacAddr6,
+    label: Label,
+    color: Hsbk,
+    power: u16,
+    vendor: u32,
+    product: u32,
+    firmware: FirmwareVersion,
+}
+
+impl VirtualDevice {
+    /// Create a new device that is switched on and set to a neutral white. It identifies itself as a LIFX Color bulb running firmware 3.70, unless
+    /// configured otherwise.
+    pub fn new(mac: MacAddr6, label: Label) -> VirtualDevice {
+        VirtualDevice {
+            mac,
+            label,
+            color: Hsbk {
+                hue: 0,
+                saturation: 0,
+                brightness: u16::MAX,
+                temperature: Kelvin::new(3500),
+            },
+            power: u16::MAX,
+            vendor: 1,
+            product: 91,
+            firmware: FirmwareVersion::new(3, 70),
+        }
+    }
+
+    pub fn with_color(self, color: Hsbk) -> VirtualDevice {
+        VirtualDevice { color, ..self }
+    }
+
+    pub fn with_power(self, power: u16) -> VirtualDevice {
+        VirtualDevice { power, ..self }
+    }
+
+    /// Identify as product `product` from vendor `vendor`, as listed in [`lifx_proto::products`]
+    pub fn with_product(self, vendor: u32, product: u32) -> VirtualDevice {
+        VirtualDevice { vendor, product, ..self }
+    }
+
+    pub fn with_firmware(self, firmware: FirmwareVersion) -> VirtualDevice {
+        VirtualDevice { firmware, ..self }
+    }
+
+    pub fn mac(&self) -> MacAddr6 {
+        self.mac
+    }
+
+    pub fn label(&self) -> &Label {
+        &self.label
+    }
+
+    pub fn color(&self) -> Hsbk {
+        self.color
+    }
+
+    pub fn power(&self) -> u16 {
+        self.power
+    }
+
+    /// Whether or not this device should handle a message sent to `target`
+    pub fn is_targeted_by(&self, target: DeviceTarget) -> bool {
+        match target {
+            DeviceTarget::All => true,
+            DeviceTarget::Targeted(mac) => mac == self.mac,
+        }
+    }
+
+    /// Apply `request` to this device, returning the packets it sends in response. `port` is the UDP port the device is listening on, which it
+    /// advertises in response to discovery.
+    pub fn handle(&mut self, request: &Packet, port: u16) -> Vec<Packet> {
+        let mut replies = Vec::new();
+        if request.acknowledgement_required() {
+            replies.push(self.reply_to(request, Message::Acknowledgement));
+        }
+
+        // Get messages are always answered, but set messages only send a response if one is required
+        let response = match request.message() {
+            Message::GetService => Some(Message::StateService(StateService { service: Service::Udp, port: port.into() })),
+            Message::GetHostFirmware => Some(Message::StateHostFirmware(StateHostFirmware { build: 0, version: self.firmware })),
+            Message::GetVersion => Some(Message::StateVersion(StateVersion { vendor: self.vendor, product: self.product })),
+            Message::GetLabel => Some(self.state_label()),
+            Message::SetLabel(inner) => {
+                self.label = inner.label;
+                Some(self.state_label()).filter(|_| request.response_required())
+            }
+            Message::Get => Some(self.state()),
+            Message::SetColor(inner) => {
+                self.color = inner.color;
+                Some(self.state()).filter(|_| request.response_required())
+            }
+            other => {
+                tracing::debug!("{} ignoring unsupported {:?} message", self.mac, other.message_type());
+                None
+            }
+        };
+
+        if let Some(response) = response {
+            replies.push(self.reply_to(request, response));
+        }
+        replies
+    }
+
+    fn state_label(&self) -> Message {
+        Message::StateLabel(StateLabel { label: self.label })
+    }
+
+    fn state(&self) -> Message {
+        Message::State(State { color: self.color, power: self.power, label: self.label })
+    }
+
+    fn reply_to(&self, request: &Packet, message: Message) -> Packet {
+        Packet::new(request.source(), DeviceTarget::Targeted(self.mac), request.sequence(), false, false, message)
+    }
+}
+
+#[test]
+fn test_set_color() {
+    use lifx_proto::duration::ProtocolDuration;
+    use lifx_proto::message::SetColor;
+
+    let mac = MacAddr6::new(0xd0, 0x73, 0xd5, 0x00, 0x00, 0x01);
+    let mut device = VirtualDevice::new(mac, Label::new("Test"));
+    let color = Hsbk { hue: 1000, saturation: 2000, brightness: 3000, temperature: Kelvin::new(2700) };
+    let message = Message::SetColor(SetColor { color, duration: ProtocolDuration::from_millis(1000) });
+
+    let request = Packet::new(42, DeviceTarget::Targeted(mac), 7, false, true, message);
+    let replies = device.handle(&request, 56700);
+    assert_eq!(device.color(), color);
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0].message(), &Message::Acknowledgement);
+    assert_eq!(replies[0].source(), 42);
+    assert_eq!(replies[0].sequence(), 7);
+    assert_eq!(replies[0].target(), DeviceTarget::Targeted(mac));
+}
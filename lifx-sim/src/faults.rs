@@ -0,0 +1,258 @@
+//! Simulated network faults, for testing how clients cope with unreliable networks.
+//!
+//! All randomness comes from a seeded RNG, so a test that fails with a given [`Faults::seed`] will fail the same way every time it's run with that
+//! seed (as long as the packets it sends are the same).
+
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use lifx_proto::Packet;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::time::Instant;
+
+/// How long a held-back reply waits for another reply to be sent before it, before it's sent anyway
+const HOLD_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Faults to inject into a [`Simulator`](crate::Simulator)'s traffic
+#[derive(Debug, Clone, PartialEq)]
+pub struct Faults {
+    /// Seed for the random number generator used to decide which faults happen
+    pub seed: u64,
+
+    /// Probability that any given packet is dropped. This applies to both incoming requests and outgoing replies.
+    pub loss: f64,
+
+    /// How long to delay each outgoing reply. Since each reply is delayed independently, random latency can also reorder replies.
+    pub latency: Latency,
+
+    /// Probability that an outgoing reply is sent twice
+    pub duplication: f64,
+
+    /// Probability that an outgoing reply is held back and sent after the next one. If no other reply is sent soon after, the held-back reply is
+    /// sent on its own.
+    pub reordering: f64,
+}
+
+impl Faults {
+    /// A perfectly reliable network, using `seed` for any faults that are enabled later
+    pub fn seeded(seed: u64) -> Faults {
+        Faults {
+            seed,
+            loss: 0.0,
+            latency: Latency::None,
+            duplication: 0.0,
+            reordering: 0.0,
+        }
+    }
+
+    /// # Panics
+    /// If `loss` isn't between 0 and 1
+    pub fn with_loss(self, loss: f64) -> Faults {
+        Faults { loss, ..self }.validated()
+    }
+
+    /// # Panics
+    /// If `latency` is a [`Latency::Uniform`] whose `min` is greater than its `max`
+    pub fn with_latency(self, latency: Latency) -> Faults {
+        Faults { latency, ..self }.validated()
+    }
+
+    /// # Panics
+    /// If `duplication` isn't between 0 and 1
+    pub fn with_duplication(self, duplication: f64) -> Faults {
+        Faults { duplication, ..self }.validated()
+    }
+
+    /// # Panics
+    /// If `reordering` isn't between 0 and 1
+    pub fn with_reordering(self, reordering: f64) -> Faults {
+        Faults { reordering, ..self }.validated()
+    }
+
+    /// Check that every probability is between 0 and 1, and that any latency range is non-empty
+    pub fn validate(&self) -> Result<(), InvalidFaults> {
+        let probabilities = [("loss", self.loss), ("duplication", self.duplication), ("reordering", self.reordering)];
+        if let Some((name, _)) = probabilities.iter().find(|(_, probability)| !(0.0..=1.0).contains(probability)) {
+            return Err(InvalidFaults(format!("{} must be a probability between 0 and 1", name)));
+        }
+        if let Latency::Uniform { min, max } = self.latency {
+            if min > max {
+                return Err(InvalidFaults(format!("minimum latency {:?} is greater than maximum latency {:?}", min, max)));
+            }
+        }
+        Ok(())
+    }
+
+    fn validated(self) -> Faults {
+        if let Err(err) = self.validate() {
+            panic!("{}", err);
+        }
+        self
+    }
+}
+
+impl Default for Faults {
+    fn default() -> Faults {
+        Faults::seeded(0)
+    }
+}
+
+/// Distribution of simulated network latency
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Latency {
+    /// Send replies immediately
+    None,
+    /// Delay every reply by the same amount
+    Fixed(Duration),
+    /// Delay each reply by a random amount, chosen uniformly between `min` and `max`
+    Uniform { min: Duration, max: Duration },
+}
+
+/// Error returned by [`Faults::validate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidFaults(String);
+
+impl fmt::Display for InvalidFaults {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid faults: {}", self.0)
+    }
+}
+
+impl Error for InvalidFaults {}
+
+/// A reply that should be sent after `delay`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Delivery {
+    pub(crate) packet: Packet,
+    pub(crate) addr: SocketAddr,
+    pub(crate) delay: Duration,
+}
+
+/// Applies [`Faults`] to a simulator's traffic
+#[derive(Debug)]
+pub(crate) struct FaultInjector {
+    faults: Faults,
+    rng: StdRng,
+    // A reply waiting to be sent after the next one, and when to give up waiting
+    held: Option<(Delivery, Instant)>,
+}
+
+impl FaultInjector {
+    /// # Panics
+    /// If `faults` is invalid
+    pub(crate) fn new(faults: Faults) -> FaultInjector {
+        let faults = faults.validated();
+        let rng = StdRng::seed_from_u64(faults.seed);
+        FaultInjector { faults, rng, held: None }
+    }
+
+    /// When the held-back reply, if there is one, should be sent if no other reply has been sent first
+    pub(crate) fn held_deadline(&self) -> Option<Instant> {
+        self.held.as_ref().map(|(_, deadline)| *deadline)
+    }
+
+    /// Stop holding back a reply, returning it so it can be sent on its own
+    pub(crate) fn release_held(&mut self) -> Option<Delivery> {
+        self.held.take().map(|(delivery, _)| delivery)
+    }
+
+    /// Whether or not an incoming request should be dropped
+    pub(crate) fn drop_request(&mut self) -> bool {
+        self.rng.gen_bool(self.faults.loss)
+    }
+
+    /// Decide how to send `replies`, all addressed to `addr`
+    pub(crate) fn plan(&mut self, replies: Vec<Packet>, addr: SocketAddr) -> Vec<Delivery> {
+        let mut deliveries = Vec::new();
+        for packet in replies {
+            if self.rng.gen_bool(self.faults.loss) {
+                tracing::debug!("Dropping reply {}", packet.sequence());
+                continue;
+            }
+
+            let delivery = Delivery { packet, addr, delay: self.latency() };
+            if self.held.is_none() && self.rng.gen_bool(self.faults.reordering) {
+                tracing::debug!("Holding back reply {}", delivery.packet.sequence());
+                self.held = Some((delivery, Instant::now() + HOLD_TIMEOUT));
+                continue;
+            }
+
+            if self.rng.gen_bool(self.faults.duplication) {
+                tracing::debug!("Duplicating reply {}", delivery.packet.sequence());
+                deliveries.push(delivery.clone());
+            }
+
+            let delay = delivery.delay;
+            deliveries.push(delivery);
+
+            // Send any held-back reply after this one
+            if let Some(mut held) = self.release_held() {
+                held.delay = held.delay.max(delay);
+                deliveries.push(held);
+            }
+        }
+        deliveries
+    }
+
+    fn latency(&mut self) -> Duration {
+        match self.faults.latency {
+            Latency::None => Duration::from_secs(0),
+            Latency::Fixed(latency) => latency,
+            Latency::Uniform { min, max } => self.rng.gen_range(min..=max),
+        }
+    }
+}
+
+#[test]
+fn test_fault_injection() {
+    use lifx_proto::{DeviceTarget, Message};
+
+    let addr: SocketAddr = "127.0.0.1:56700".parse().unwrap();
+    let replies: Vec<_> = (0..20)
+        .map(|sequence| Packet::new(1, DeviceTarget::All, sequence, false, false, Message::Acknowledgement))
+        .collect();
+    let faults = Faults::seeded(1234)
+        .with_loss(0.2)
+        .with_latency(Latency::Uniform { min: Duration::from_millis(1), max: Duration::from_millis(50) })
+        .with_duplication(0.2)
+        .with_reordering(0.2);
+
+    // The same seed always produces the same faults
+    let first = FaultInjector::new(faults.clone()).plan(replies.clone(), addr);
+    let second = FaultInjector::new(faults.clone()).plan(replies.clone(), addr);
+    assert_eq!(first, second);
+    assert_ne!(first.len(), replies.len());
+
+    // Without faults, every reply is sent immediately and in order
+    let reliable = FaultInjector::new(Faults::default()).plan(replies.clone(), addr);
+    let sequences: Vec<_> = reliable.iter().map(|delivery| delivery.packet.sequence()).collect();
+    assert_eq!(sequences, (0..20).collect::<Vec<_>>());
+    assert!(reliable.iter().all(|delivery| delivery.delay == Duration::from_secs(0)));
+
+    // With total loss, nothing is sent
+    assert!(FaultInjector::new(Faults::seeded(1).with_loss(1.0)).plan(replies.clone(), addr).is_empty());
+
+    // A held-back reply waits for the next one, but can be released if there isn't one
+    let mut reordering = FaultInjector::new(Faults::seeded(1).with_reordering(1.0));
+    assert!(reordering.plan(replies[..1].to_vec(), addr).is_empty());
+    assert!(reordering.held_deadline().is_some());
+    assert_eq!(reordering.release_held().map(|delivery| delivery.packet), Some(replies[0].clone()));
+    assert_eq!(reordering.held_deadline(), None);
+}
+
+#[test]
+fn test_validate() {
+    assert_eq!(Faults::default().validate(), Ok(()));
+    for probability in [-0.1, 1.5, f64::NAN] {
+        assert!(Faults { loss: probability, ..Faults::default() }.validate().is_err());
+        assert!(Faults { duplication: probability, ..Faults::default() }.validate().is_err());
+        assert!(Faults { reordering: probability, ..Faults::default() }.validate().is_err());
+    }
+
+    let backwards = Latency::Uniform { min: Duration::from_millis(10), max: Duration::from_millis(5) };
+    assert!(Faults { latency: backwards, ..Faults::default() }.validate().is_err());
+    assert!(std::panic::catch_unwind(|| Faults::default().with_latency(backwards)).is_err());
+}
@@ -0,0 +1,206 @@
+//! Simulated LIFX devices, for testing clients without real hardware.
+//!
+//! A [`Simulator`] hosts any number of [`VirtualDevice`]s on a single UDP socket. Each device has its own MAC address, and responds to discovery
+//! separately, so clients see them as distinct devices that happen to share an IP address and port.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use lifx_proto::{Header, Packet};
+use macaddr::MacAddr6;
+use tokio::net::{ToSocketAddrs, UdpSocket};
+
+mod device;
+pub mod faults;
+
+pub use device::VirtualDevice;
+pub use faults::{Faults, InvalidFaults, Latency};
+
+use faults::{Delivery, FaultInjector};
+
+/// Maximum packet size the simulator will accept
+const MAX_PACKET_SIZE: usize = 4 * 1024;
+
+/// Shared handle to the devices hosted by a [`Simulator`]. This can be used to inspect and modify device state while the simulator is running.
+#[derive(Debug, Clone, Default)]
+pub struct Devices(Arc<Mutex<BTreeMap<MacAddr6, VirtualDevice>>>);
+
+impl Devices {
+    /// Add a device, replacing any existing device with the same MAC address
+    pub fn add(&self, device: VirtualDevice) {
+        self.0.lock().unwrap().insert(device.mac(), device);
+    }
+
+    /// Remove the device with MAC address `mac`, returning it if it existed
+    pub fn remove(&self, mac: MacAddr6) -> Option<VirtualDevice> {
+        self.0.lock().unwrap().remove(&mac)
+    }
+
+    /// Get a snapshot of the current state of the device with MAC address `mac`
+    pub fn get(&self, mac: MacAddr6) -> Option<VirtualDevice> {
+        self.0.lock().unwrap().get(&mac).cloned()
+    }
+
+    /// MAC addresses of all hosted devices
+    pub fn macs(&self) -> Vec<MacAddr6> {
+        self.0.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Handle `request` on every device it targets, returning all of their responses
+    fn handle(&self, request: &Packet, port: u16) -> Vec<Packet> {
+        let mut devices = self.0.lock().unwrap();
+        devices
+            .values_mut()
+            .filter(|device| device.is_targeted_by(request.target()))
+            .flat_map(|device| device.handle(request, port))
+            .collect()
+    }
+}
+
+/// Hosts simulated devices on a UDP socket
+pub struct Simulator {
+    socket: Arc<UdpSocket>,
+    devices: Devices,
+    faults: Option<FaultInjector>,
+}
+
+impl Simulator {
+    /// Create a new simulator with no devices, bound to `addr`. Use port 0 to pick an unused port, which is usually what tests want.
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Simulator> {
+        let socket = UdpSocket::bind(addr).await?;
+        socket.set_broadcast(true)?;
+        Ok(Simulator {
+            socket: Arc::new(socket),
+            devices: Devices::default(),
+            faults: None,
+        })
+    }
+
+    /// Inject `faults` into all traffic to and from this simulator
+    ///
+    /// # Panics
+    /// If `faults` is invalid. Use [`Faults::validate`] to check them first.
+    pub fn with_faults(self, faults: Faults) -> Simulator {
+        Simulator { faults: Some(FaultInjector::new(faults)), ..self }
+    }
+
+    /// Address the simulator is listening on. Clients should send discovery messages here.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Handle to the devices hosted by this simulator
+    pub fn devices(&self) -> Devices {
+        self.devices.clone()
+    }
+
+    /// Add a device to this simulator
+    pub fn add_device(&self, device: VirtualDevice) {
+        self.devices.add(device);
+    }
+
+    /// Receive and respond to messages forever. This is usually spawned as a background task.
+    pub async fn run(mut self) -> io::Result<()> {
+        let port = self.local_addr()?.port();
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        loop {
+            let received = match self.faults.as_ref().and_then(FaultInjector::held_deadline) {
+                Some(deadline) => tokio::time::timeout_at(deadline, self.socket.recv_from(&mut buf)).await,
+                None => Ok(self.socket.recv_from(&mut buf).await),
+            };
+            let (len, from) = match received {
+                Ok(received) => received?,
+                Err(_) => {
+                    // No other reply came along to send the held-back one after, so send it on its own
+                    if let Some(delivery) = self.faults.as_mut().and_then(FaultInjector::release_held) {
+                        deliver(&self.socket, delivery).await?;
+                    }
+                    continue;
+                }
+            };
+            let request = match decode(&buf[..len]) {
+                Some(request) => request,
+                None => {
+                    tracing::debug!("Ignoring invalid {}-byte packet from {}", len, from);
+                    continue;
+                }
+            };
+            tracing::trace!("Received {:?} from {}", request, from);
+
+            if let Some(ref mut faults) = self.faults {
+                if faults.drop_request() {
+                    tracing::debug!("Dropping request {} from {}", request.sequence(), from);
+                    continue;
+                }
+            }
+
+            let replies = self.devices.handle(&request, port);
+            let deliveries = match self.faults {
+                Some(ref mut faults) => faults.plan(replies, from),
+                None => replies
+                    .into_iter()
+                    .map(|packet| Delivery { packet, addr: from, delay: Duration::from_secs(0) })
+                    .collect(),
+            };
+
+            for delivery in deliveries {
+                deliver(&self.socket, delivery).await?;
+            }
+        }
+    }
+}
+
+/// Send `delivery` now, or in the background if it's delayed
+async fn deliver(socket: &Arc<UdpSocket>, delivery: Delivery) -> io::Result<()> {
+    if delivery.delay == Duration::from_secs(0) {
+        send(socket, &delivery.packet, delivery.addr).await
+    } else {
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delivery.delay).await;
+            if let Err(err) = send(&socket, &delivery.packet, delivery.addr).await {
+                tracing::warn!("Could not send delayed reply: {}", err);
+            }
+        });
+        Ok(())
+    }
+}
+
+async fn send(socket: &UdpSocket, packet: &Packet, addr: SocketAddr) -> io::Result<()> {
+    let mut out = BytesMut::with_capacity(packet.len());
+    packet.encode(&mut out).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    socket.send_to(&out, addr).await?;
+    Ok(())
+}
+
+/// Decode a packet, rejecting any whose size doesn't match the amount of data received
+fn decode(mut data: &[u8]) -> Option<Packet> {
+    if data.len() < Header::HEADER_SIZE {
+        return None;
+    }
+    let size = u16::from_le_bytes([data[0], data[1]]) as usize;
+    if size != data.len() {
+        return None;
+    }
+    Packet::decode(&mut data).ok()
+}
+
+#[test]
+fn test_decode_short_payload() {
+    use lifx_proto::message::GetLabel;
+    use lifx_proto::MessageType;
+
+    // A valid header claiming a SetColor, but without its payload, is dropped rather than crashing the simulator
+    let packet = Packet::builder(GetLabel).build();
+    let mut data = Vec::new();
+    packet.encode(&mut data).unwrap();
+    data[32..34].copy_from_slice(&u16::from(MessageType::SetColor).to_le_bytes());
+    assert_eq!(decode(&data), None);
+
+    data[32..34].copy_from_slice(&u16::from(packet.message().message_type()).to_le_bytes());
+    assert_eq!(decode(&data), Some(packet));
+}
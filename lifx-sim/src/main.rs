@@ -0,0 +1,75 @@
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+
+use lifx_proto::label::Label;
+use lifx_sim::{Faults, Latency, Simulator, VirtualDevice};
+use macaddr::MacAddr6;
+
+const USAGE: &str = "usage: lifx-sim [--bind ADDRESS] [--devices COUNT] [--seed SEED] [--loss PROBABILITY] [--latency MILLIS]
+                [--duplication PROBABILITY] [--reordering PROBABILITY]";
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_ansi(true)
+        .with_max_level(tracing::Level::DEBUG)
+        .init();
+
+    let mut bind = "0.0.0.0:56700".to_string();
+    let mut count = 1u16;
+    let mut faults = Faults::default();
+    let mut inject_faults = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next();
+        match (arg.as_str(), value) {
+            ("--bind", Some(value)) => bind = value,
+            ("--devices", Some(value)) => count = parse(&value),
+            ("--seed", Some(value)) => faults.seed = parse(&value),
+            ("--loss", Some(value)) => faults.loss = parse(&value),
+            ("--latency", Some(value)) => faults.latency = Latency::Fixed(Duration::from_millis(parse(&value))),
+            ("--duplication", Some(value)) => faults.duplication = parse(&value),
+            ("--reordering", Some(value)) => faults.reordering = parse(&value),
+            _ => usage(),
+        }
+        inject_faults |= arg != "--bind" && arg != "--devices";
+    }
+
+    let mut simulator = Simulator::bind(&bind).await.unwrap_or_else(|err| {
+        eprintln!("Could not bind to {}: {}", bind, err);
+        process::exit(1);
+    });
+    if inject_faults {
+        if let Err(err) = faults.validate() {
+            eprintln!("{}", err);
+            usage();
+        }
+        tracing::info!("Injecting faults: {:?}", faults);
+        simulator = simulator.with_faults(faults);
+    }
+
+    for i in 1..=count {
+        let [hi, lo] = i.to_be_bytes();
+        let mac = MacAddr6::new(0xd0, 0x73, 0xd5, 0x00, hi, lo);
+        let device = VirtualDevice::new(mac, Label::new(format!("Virtual Bulb {}", i)));
+        tracing::info!("Simulating {} ({})", device.label(), mac);
+        simulator.add_device(device);
+    }
+
+    tracing::info!("Listening on {}", simulator.local_addr().unwrap());
+    if let Err(err) = simulator.run().await {
+        tracing::error!("Simulator died: {}", err);
+        process::exit(1);
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> T {
+    value.parse().unwrap_or_else(|_| usage())
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}